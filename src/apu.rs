@@ -0,0 +1,804 @@
+// 2A03 audio: two pulse channels, triangle, noise and DMC, sequenced by the frame counter and mixed into PCM samples
+use crate::bus::Device;
+
+// NTSC cpu clock in Hz, the APU runs off the same clock
+const CPU_CLOCK: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// seconds of audio kept when nobody drains the sample buffer
+const MAX_BUFFERED_SECONDS: usize = 4;
+
+// frame counter steps, in cpu cycles
+const QUARTER_FRAME_1: u32 = 7457;
+const HALF_FRAME_1: u32 = 14913;
+const QUARTER_FRAME_3: u32 = 22371;
+const FOUR_STEP_END: u32 = 29829;
+const FIVE_STEP_END: u32 = 37281;
+
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+static DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+static TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// in APU cycles
+static NOISE_PERIOD_TABLE: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+// in cpu cycles
+static DMC_RATE_TABLE: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// counts down to silence the channel, unless halted
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    // half frame
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+// volume that either stays constant or decays from 15, optionally looping
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // constant volume, or the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    // quarter frame
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+struct Pulse {
+    // pulse 1 negates with one's complement, so its sweep goes one further down
+    ones_complement: bool,
+    duty: u8,
+    duty_step: u8,
+    timer_period: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            duty_step: 0,
+            timer_period: 0,
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length.load(data);
+                self.envelope.start = true;
+                self.duty_step = 0;
+            }
+        }
+    }
+
+    // period the sweep unit is heading for
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = if self.ones_complement { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // too low a period, or a sweep heading past $7FF, silences the channel
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // half frame
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // every APU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.muted() || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+    // the control flag doubles as the length counter halt
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    // quarter frame
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.length.halt {
+            self.linear_reload = false;
+        }
+    }
+
+    // every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // stopping the sequencer holds the last level rather than dropping to 0
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+struct Noise {
+    // short mode taps bit 6 instead of bit 1, giving a metallic 93 step loop
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            short_mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift: 1,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(data & 0x0F) as usize];
+            }
+            3 => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    // every APU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// delta modulation channel, plays 1 bit deltas fetched from cpu memory
+#[derive(Default)]
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = DMC_RATE_TABLE[(data & 0x0F) as usize];
+            }
+            1 => self.output_level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) + 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    // address the reader wants next, when the buffer is empty and there is sample left
+    fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // the address wraps from $FFFF to $8000
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate.saturating_sub(1);
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        if self.bits_remaining > 0 {
+            self.bits_remaining -= 1;
+        }
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    five_step: bool,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // pulse and noise timers tick every other cpu cycle
+    odd_cycle: bool,
+    sample_rate: u32,
+    // cpu cycles into the current sample, scaled by the sample rate
+    sample_clock: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::default(),
+            five_step: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sample_rate,
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            samples: vec![],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // samples produced since the last call, mono, 0.0 (every channel silent) to about 1.0.
+    // That's the DAC level, not centred audio: see wav::remove_dc
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // frame counter or DMC holding the IRQ line
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // $4015 read: length counters and IRQ flags. Reading acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        status |= self.pulse_1.length.active() as u8;
+        status |= (self.pulse_2.length.active() as u8) << 1;
+        status |= (self.triangle.length.active() as u8) << 2;
+        status |= (self.noise.length.active() as u8) << 3;
+        status |= ((self.dmc.bytes_remaining > 0) as u8) << 4;
+        status |= (self.frame_irq as u8) << 6;
+        status |= (self.dmc.irq as u8) << 7;
+        self.frame_irq = false;
+        status
+    }
+
+    // register writes by address, $4000-$4013, $4015 and $4017
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse_1.write(addr & 0b11, data),
+            0x4004..=0x4007 => self.pulse_2.write(addr & 0b11, data),
+            0x4008..=0x400B => self.triangle.write(addr & 0b11, data),
+            0x400C..=0x400F => self.noise.write(addr & 0b11, data),
+            0x4010..=0x4013 => self.dmc.write(addr & 0b11, data),
+            0x4015 => {
+                self.pulse_1.length.set_enabled(data & 0b1 != 0);
+                self.pulse_2.length.set_enabled(data & 0b10 != 0);
+                self.triangle.length.set_enabled(data & 0b100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            0x4017 => {
+                self.five_step = data & 0x80 != 0;
+                self.frame_irq_inhibit = data & 0x40 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // 5 step mode clocks everything straight away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // address of the next DMC sample byte, if the channel wants one. The bus reads it,
+    // hands it over with dmc_fill and stalls the cpu while doing so
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        match (self.five_step, self.frame_cycle) {
+            (_, QUARTER_FRAME_1) | (_, QUARTER_FRAME_3) => self.quarter_frame(),
+            (_, HALF_FRAME_1) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (false, FOUR_STEP_END) => {
+                self.quarter_frame();
+                self.half_frame();
+                if !self.frame_irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            (false, cycle) if cycle > FOUR_STEP_END => self.frame_cycle = 0,
+            (true, FIVE_STEP_END) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (true, cycle) if cycle > FIVE_STEP_END => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    // non-linear DAC, using the usual approximation of the two resistor networks
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output_level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+
+    // advance by one cpu cycle
+    pub fn clock(&mut self) {
+        self.clock_frame_counter();
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        // average the output over each sample period
+        self.sample_sum += self.mix();
+        self.sample_count += 1;
+        self.sample_clock += self.sample_rate as f64;
+        if self.sample_clock >= CPU_CLOCK {
+            self.sample_clock -= CPU_CLOCK;
+            self.samples.push(self.sample_sum / self.sample_count as f32);
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+            let limit = self.sample_rate as usize * MAX_BUFFERED_SECONDS;
+            if self.samples.len() > limit {
+                self.samples.drain(..limit / 2);
+            }
+        }
+    }
+}
+
+// registers at $4000-$4015, seen as offsets from $4000. Only $4015 can be read
+impl Device for Apu {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0x15 => self.read_status(),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.write_register(0x4000 + offset, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn test_length_counters_and_status() {
+        let mut apu = Apu::default();
+        // disabled channels ignore length loads
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b1, 0);
+
+        apu.write_register(0x4015, 0b0000_1111);
+        // length index 1 is 254, index 3 is 2
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4007, 0b0001_1000);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0x0F, 0x0F);
+        // two half frames run pulse 2 out
+        run(&mut apu, FOUR_STEP_END);
+        assert_eq!(apu.read_status() & 0x0F, 0b1101);
+
+        // disabling a channel clears its length
+        apu.write_register(0x4015, 0b0000_0001);
+        assert_eq!(apu.read_status() & 0x0F, 0b0001);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::default();
+        run(&mut apu, FOUR_STEP_END - 1);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        // reading status acknowledges it
+        assert!(!apu.irq());
+
+        // inhibited, and 5 step mode never raises it
+        apu.write_register(0x4017, 0x40);
+        run(&mut apu, 2 * FOUR_STEP_END);
+        assert!(!apu.irq());
+        apu.write_register(0x4017, 0x80);
+        run(&mut apu, 2 * FIVE_STEP_END);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::default();
+        envelope.write(0x00);
+        envelope.start = true;
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 0);
+
+        // looping starts over from 15
+        envelope.write(0x20);
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        // constant volume ignores the decay
+        envelope.write(0x17);
+        assert_eq!(envelope.output(), 7);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut pulse = Pulse::new(true);
+        pulse.timer_period = 0x100;
+        // enabled, period 0, negate, shift 1
+        pulse.write(1, 0b1000_1001);
+        assert_eq!(pulse.sweep_target(), 0x100 - 0x80 - 1);
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x7F);
+
+        // a target past $7FF mutes without touching the period
+        let mut pulse = Pulse::new(false);
+        pulse.timer_period = 0x600;
+        pulse.write(1, 0b1000_0001);
+        assert!(pulse.muted());
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x600);
+    }
+
+    #[test]
+    fn test_pulse_produces_samples() {
+        let mut apu = Apu::new(44100);
+        apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 15, period 253 (about 440 Hz)
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0b0000_1000);
+        run(&mut apu, CPU_CLOCK as u32 / 10);
+        let samples = apu.take_samples();
+        assert!((4409..=4411).contains(&samples.len()), "{} samples", samples.len());
+        // the idle triangle sits at level 15, the square wave swings on top of that
+        let loudest = samples.iter().cloned().fold(f32::MIN, f32::max);
+        let quietest = samples.iter().cloned().fold(f32::MAX, f32::min);
+        let swing = loudest - quietest;
+        assert!((0.14..0.16).contains(&swing), "{}", swing);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_dmc_fetch_and_irq() {
+        let mut apu = Apu::default();
+        // IRQ at the end, fastest rate, 17 bytes from $C040
+        apu.write_register(0x4010, 0x8F);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+        for i in 0..17 {
+            assert_eq!(apu.dmc_fetch_address(), Some(0xC040 + i));
+            apu.dmc_fill(0xFF);
+            assert_eq!(apu.dmc_fetch_address(), None);
+            // the buffer empties into the shift register after 8 output bits
+            run(&mut apu, 8 * 54);
+        }
+        assert_eq!(apu.dmc_fetch_address(), None);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x90, 0x80);
+        // all ones raised the level, capped at 127
+        assert!(apu.dmc.output_level > 100);
+    }
+}
@@ -1,34 +1,420 @@
+use crate::apu::Apu;
 use crate::cpu::Mem;
+use crate::cartridge::{Rom, RomError};
+use crate::joypad::{JoypadButton, JoypadPorts};
+use crate::mapper::{self, SharedMapper};
+use crate::ppu::Ppu;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
 
+// default NES cpu memory map
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
+const RAM_SIZE: usize = 2048;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PPU_DOTS_PER_CPU_CYCLE: u64 = 3;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4015;
+// writes go to the APU frame counter, reads to the second controller
+const APU_FRAME_COUNTER: u16 = 0x4017;
+// cycles the cpu loses while the DMC fetches a sample byte
+const DMC_FETCH_CYCLES: u64 = 4;
+const JOYPADS: u16 = 0x4016;
+const JOYPADS_END: u16 = 0x4017;
+// writing a page number here copies that page into OAM
+const OAM_DMA: u16 = 0x4014;
+// one cycle to halt the cpu, then a read and a write per byte. Starting on an odd cycle costs one more
+const OAM_DMA_CYCLES: u64 = 513;
+// NTSC frame length in cpu cycles, used to pace frames when there is no PPU
+const CPU_CYCLES_PER_FRAME: u64 = 29781;
+const CARTRIDGE: u16 = 0x4020;
+const CARTRIDGE_END: u16 = 0xFFFF;
+
+// anything that can be mapped into the address space. Reads and writes are given the offset
+// from the start of the region the device was registered at
+pub trait Device {
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, data: u8);
+
+    // read-only devices never see writes, the bus drops them (and can report them as faults)
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+// a device the bus shares with the rest of the machine
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.borrow_mut().write(offset, data);
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.borrow().is_read_only()
+    }
+}
+
+// plain storage backing RAM and ROM regions. A region larger than the storage mirrors it
+pub struct Memory {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl Memory {
+    pub fn ram(size: usize) -> Self {
+        Memory {
+            data: vec![0; size],
+            writable: true,
+        }
+    }
+
+    pub fn rom(data: Vec<u8>) -> Self {
+        Memory {
+            data,
+            writable: false,
+        }
+    }
+}
+
+impl Device for Memory {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.data[offset as usize % self.data.len()]
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if self.writable {
+            let len = self.data.len();
+            self.data[offset as usize % len] = data;
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        !self.writable
+    }
+}
+
+// the cartridge slot, hands CPU accesses to the mapper with their absolute address
+struct CartridgeSlot {
+    mapper: SharedMapper,
+}
+
+impl Device for CartridgeSlot {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.mapper.borrow_mut().cpu_read(CARTRIDGE + offset)
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.mapper.borrow_mut().cpu_write(CARTRIDGE + offset, data);
+    }
+}
+
+// an address range and what answers it. No device means the range is reserved for hardware
+// we cannot emulate, and any access to it is reported as a fault
+struct Region {
+    start: u16,
+    end: u16,
+    device: Option<Box<dyn Device>>,
+}
+
+// memory map that can't be built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    // region ends before it starts
+    BackwardsRegion { start: u16, end: u16 },
+    // RAM or ROM with no bytes to mirror across its region
+    EmptyMemory { start: u16, end: u16 },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::BackwardsRegion { start, end } => {
+                write!(f, "region {:#06x}-{:#06x} ends before it starts", start, end)
+            }
+            BusError::EmptyMemory { start, end } => {
+                write!(f, "memory at {:#06x}-{:#06x} has no bytes to mirror", start, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+// assembles a memory map region by region. Regions are looked up in the order they were added,
+// so an earlier region wins where two overlap. Addresses outside every region read as 0.
+// The first bad region is kept and reported by build
+#[derive(Default)]
+pub struct BusBuilder {
+    regions: Vec<Region>,
+    rom_write_faults: bool,
+    mapper: Option<SharedMapper>,
+    ppu: Option<Rc<RefCell<Ppu>>>,
+    joypads: Option<Rc<RefCell<JoypadPorts>>>,
+    apu: Option<Rc<RefCell<Apu>>>,
+    error: Option<BusError>,
+}
+
+impl BusBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // RAM of the given size, mirrored across start..=end
+    pub fn ram(self, start: u16, end: u16, size: usize) -> Self {
+        if size == 0 {
+            return self.fail(BusError::EmptyMemory { start, end });
+        }
+        self.device(start, end, Memory::ram(size))
+    }
+
+    // ROM holding data, mirrored across start..=end. Writes are ignored
+    pub fn rom(self, start: u16, end: u16, data: Vec<u8>) -> Self {
+        if data.is_empty() {
+            return self.fail(BusError::EmptyMemory { start, end });
+        }
+        self.device(start, end, Memory::rom(data))
+    }
+
+    // ROM image loaded from a binary file, mapped from base up to the end of the file
+    pub fn rom_file<P: AsRef<Path>>(self, base: u16, path: P) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.is_empty() || base as usize + data.len() > 0x10000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} byte ROM does not fit at {:#06x}", data.len(), base),
+            ));
+        }
+        let end = (base as usize + data.len() - 1) as u16;
+        Ok(self.rom(base, end, data))
+    }
+
+    // report writes to ROM as bus faults instead of silently dropping them
+    pub fn rom_write_faults(mut self, enabled: bool) -> Self {
+        self.rom_write_faults = enabled;
+        self
+    }
+
+    // memory mapped device answering start..=end
+    pub fn device<D: Device + 'static>(mut self, start: u16, end: u16, device: D) -> Self {
+        if start > end {
+            return self.fail(BusError::BackwardsRegion { start, end });
+        }
+        self.regions.push(Region { start, end, device: Some(Box::new(device)) });
+        self
+    }
+
+    // cartridge mapper answering $4020-$FFFF
+    pub fn mapper(mut self, mapper: SharedMapper) -> Self {
+        self.mapper = Some(mapper.clone());
+        self.device(CARTRIDGE, CARTRIDGE_END, CartridgeSlot { mapper })
+    }
+
+    // PPU registers at $2000-$3FFF
+    pub fn ppu(mut self, ppu: Ppu) -> Self {
+        let ppu = Rc::new(RefCell::new(ppu));
+        self.ppu = Some(ppu.clone());
+        self.device(PPU_REGISTERS, PPU_REGISTERS_MIRRORS_END, ppu)
+    }
+
+    // APU registers at $4000-$4015, plus the frame counter at $4017
+    pub fn apu(mut self, apu: Apu) -> Self {
+        let apu = Rc::new(RefCell::new(apu));
+        self.apu = Some(apu.clone());
+        self.device(APU_REGISTERS, APU_REGISTERS_END, apu)
+    }
+
+    // both controller ports at $4016-$4017
+    pub fn joypads(mut self) -> Self {
+        let joypads = Rc::new(RefCell::new(JoypadPorts::default()));
+        self.joypads = Some(joypads.clone());
+        self.device(JOYPADS, JOYPADS_END, joypads)
+    }
+
+    // range reserved for hardware that is not emulated, accesses raise a bus fault
+    pub fn unsupported(mut self, start: u16, end: u16) -> Self {
+        if start > end {
+            return self.fail(BusError::BackwardsRegion { start, end });
+        }
+        self.regions.push(Region { start, end, device: None });
+        self
+    }
+
+    fn fail(mut self, error: BusError) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+
+    pub fn build(self) -> Result<Bus, BusError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        Ok(Bus {
+            regions: self.regions,
+            rom_write_faults: self.rom_write_faults,
+            mapper: self.mapper,
+            ppu: self.ppu,
+            joypads: self.joypads,
+            apu: self.apu,
+            fault: None,
+            cycles: 0,
+            frame_complete: false,
+            dma_pending: false,
+            stall: 0,
+        })
+    }
+}
 
 pub struct Bus {
-    cpu_vram: [u8; 2048]
+    regions: Vec<Region>,
+    rom_write_faults: bool,
+    mapper: Option<SharedMapper>,
+    ppu: Option<Rc<RefCell<Ppu>>>,
+    joypads: Option<Rc<RefCell<JoypadPorts>>>,
+    apu: Option<Rc<RefCell<Apu>>>,
+    // last access that hit hardware we cannot emulate
+    fault: Option<u16>,
+    // cpu cycles run since power on
+    cycles: u64,
+    // vblank started since the last call to take_frame_complete
+    frame_complete: bool,
+    // an OAM DMA ran during the current instruction, its stall is worked out once the instruction ends
+    dma_pending: bool,
+    stall: u64,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
+    // NES cpu memory map: 2 KiB of RAM mirrored up to $1FFF, PPU registers at $2000-$3FFF
     pub fn new() -> Self{
-        Bus {
-            cpu_vram: [0; 2048]
+        Bus::builder()
+            .ram(RAM, RAM_MIRRORS_END, RAM_SIZE)
+            .ppu(Ppu::default())
+            .apu(Apu::default())
+            .joypads()
+            .build()
+            .expect("the NES memory map is valid")
+    }
+
+    // NES memory map with a cartridge's mapper behind $4020-$FFFF. Fails for boards we have no mapper for
+    pub fn with_rom(rom: &Rom) -> Result<Self, RomError> {
+        let mapper = mapper::for_rom(rom)?;
+        Ok(Bus::builder()
+            .ram(RAM, RAM_MIRRORS_END, RAM_SIZE)
+            .ppu(Ppu::new(Some(mapper.clone())))
+            .apu(Apu::default())
+            .joypads()
+            .mapper(mapper)
+            .build()
+            .expect("the NES memory map is valid"))
+    }
+
+    // the cartridge's mapper, shared with whatever else needs CHR
+    pub fn mapper(&self) -> Option<SharedMapper> {
+        self.mapper.clone()
+    }
+
+    // restore the cartridge's PRG-RAM from a save file. A missing file leaves it blank,
+    // a file of the wrong size fills what it can and gives back (file size, RAM size) to warn about
+    pub fn load_prg_ram<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Option<(usize, usize)>> {
+        let mapper = match &self.mapper {
+            Some(mapper) => mapper,
+            None => return Ok(None),
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut mapper = mapper.borrow_mut();
+        let ram = mapper.prg_ram_mut();
+        let len = ram.len().min(data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        if data.len() != ram.len() {
+            return Ok(Some((data.len(), ram.len())));
         }
+        Ok(None)
+    }
+
+    // write the cartridge's PRG-RAM out, if it has any
+    pub fn save_prg_ram<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if let Some(mapper) = &self.mapper {
+            let mapper = mapper.borrow();
+            if !mapper.prg_ram().is_empty() {
+                fs::write(path, mapper.prg_ram())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ppu(&self) -> Option<Rc<RefCell<Ppu>>> {
+        self.ppu.clone()
+    }
+
+    pub fn apu(&self) -> Option<Rc<RefCell<Apu>>> {
+        self.apu.clone()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // buttons held on controller 0 or 1, for the frontend to update once per frame
+    pub fn set_buttons(&mut self, player: usize, buttons: JoypadButton) {
+        if let Some(joypads) = &self.joypads {
+            joypads.borrow_mut().joypads[player].buttons = buttons;
+        }
+    }
+
+    // whether a frame finished since the previous call
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    pub fn builder() -> BusBuilder {
+        BusBuilder::new()
+    }
+
+    // copy $XX00-$XXFF into OAM, starting at the current OAM address
+    fn oam_dma(&mut self, ppu: &Rc<RefCell<Ppu>>, page: u8) {
+        let base = (page as u16) << 8;
+        for offset in 0..=0xFF {
+            let data = self.mem_read(base + offset);
+            ppu.borrow_mut().write_oam_data(data);
+        }
+        self.dma_pending = true;
+    }
+
+    fn region_mut(&mut self, addr: u16) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| region.start <= addr && addr <= region.end)
     }
 }
 
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
-        match addr {
-            RAM ..= RAM_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b00000111_11111111;
-                self.cpu_vram[mirror_down_addr as usize]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match self.region_mut(addr) {
+            Some(Region { start, device: Some(device), .. }) => {
+                let offset = addr - *start;
+                device.read(offset)
             }
-            PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet")
+            Some(Region { device: None, .. }) => {
+                self.fault = Some(addr);
+                0
             }
-            _ => {
+            None => {
                 //println!("Ignoring mem access at {}", addr);
                 0
             }
@@ -36,18 +422,320 @@ impl Mem for Bus {
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        match addr {
-            RAM ..= RAM_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b11111111111;
-                self.cpu_vram[mirror_down_addr as usize] = data;
+        if addr == OAM_DMA {
+            if let Some(ppu) = self.ppu.clone() {
+                self.oam_dma(&ppu, data);
+                return;
             }
-            PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet");
+        }
+        if addr == APU_FRAME_COUNTER {
+            if let Some(apu) = &self.apu {
+                apu.borrow_mut().write_register(addr, data);
+                return;
+            }
+        }
+        let rom_write_faults = self.rom_write_faults;
+        match self.region_mut(addr) {
+            // writes to ROM are dropped, and optionally reported
+            Some(Region { device: Some(device), .. }) if device.is_read_only() && rom_write_faults => {
+                self.fault = Some(addr);
             }
-            _ => {
+            Some(Region { device: Some(device), .. }) if device.is_read_only() => {}
+            Some(Region { start, device: Some(device), .. }) => {
+                let offset = addr - *start;
+                device.write(offset, data);
+            }
+            Some(Region { device: None, .. }) => {
+                self.fault = Some(addr);
+            }
+            None => {
                 //println!("Ignoring mem write-access at {}", addr);
             }
         }
     }
-}
\ No newline at end of file
+
+    fn take_fault(&mut self) -> Option<u16> {
+        self.fault.take()
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        let frame_before = self.cycles / CPU_CYCLES_PER_FRAME;
+        self.cycles += cycles;
+        if let Some(mapper) = &self.mapper {
+            mapper.borrow_mut().tick(cycles);
+        }
+        // DMA takes over from the cycle after the instruction that triggered it
+        if self.dma_pending {
+            self.dma_pending = false;
+            self.stall += OAM_DMA_CYCLES + self.cycles % 2;
+        }
+        if let Some(apu) = self.apu.clone() {
+            for _ in 0..cycles {
+                apu.borrow_mut().clock();
+                // the DMC reads its samples through the bus like the cpu would
+                let fetch = apu.borrow().dmc_fetch_address();
+                if let Some(addr) = fetch {
+                    let data = self.mem_read(addr);
+                    apu.borrow_mut().dmc_fill(data);
+                    self.stall += DMC_FETCH_CYCLES;
+                }
+            }
+        }
+        match &self.ppu {
+            Some(ppu) => {
+                if ppu.borrow_mut().tick(cycles * PPU_DOTS_PER_CPU_CYCLE) {
+                    self.frame_complete = true;
+                }
+            }
+            None => {
+                if self.cycles / CPU_CYCLES_PER_FRAME != frame_before {
+                    self.frame_complete = true;
+                }
+            }
+        }
+    }
+
+    fn take_stall_cycles(&mut self) -> u64 {
+        std::mem::take(&mut self.stall)
+    }
+
+    fn irq(&self) -> bool {
+        match &self.apu {
+            Some(apu) => apu.borrow().irq(),
+            None => false,
+        }
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        match &self.ppu {
+            Some(ppu) => ppu.borrow_mut().poll_nmi(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // records every write it sees
+    struct Recorder {
+        writes: Rc<RefCell<Vec<(u16, u8)>>>,
+    }
+
+    impl Device for Recorder {
+        fn read(&mut self, offset: u16) -> u8 {
+            offset as u8
+        }
+
+        fn write(&mut self, offset: u16, data: u8) {
+            self.writes.borrow_mut().push((offset, data));
+        }
+    }
+
+    #[test]
+    fn test_ram_mirroring() {
+        let mut bus = Bus::new();
+        bus.mem_write(0x0012, 0x34);
+        assert_eq!(bus.mem_read(0x0812), 0x34);
+        assert_eq!(bus.mem_read(0x1812), 0x34);
+        assert_eq!(bus.take_fault(), None);
+    }
+
+    #[test]
+    fn test_rom_ignores_writes() {
+        let mut bus = Bus::builder().rom(0x8000, 0xFFFF, vec![0xaa, 0xbb]).build().unwrap();
+        bus.mem_write(0x8000, 0x00);
+        assert_eq!(bus.mem_read(0x8000), 0xaa);
+        assert_eq!(bus.mem_read(0xFFFF), 0xbb);
+    }
+
+    #[test]
+    fn test_bad_regions_rejected() {
+        let result = Bus::builder().ram(0x0000, 0x07FF, 0).build();
+        assert_eq!(result.err(), Some(BusError::EmptyMemory { start: 0x0000, end: 0x07FF }));
+        let result = Bus::builder().rom(0x8000, 0xFFFF, vec![]).build();
+        assert_eq!(result.err(), Some(BusError::EmptyMemory { start: 0x8000, end: 0xFFFF }));
+        let result = Bus::builder().unsupported(0x4017, 0x4000).build();
+        assert_eq!(result.err(), Some(BusError::BackwardsRegion { start: 0x4017, end: 0x4000 }));
+
+        // the first mistake is the one reported
+        let result = Bus::builder()
+            .ram(0x0000, 0x1FFF, 2048)
+            .ram(0x07FF, 0x0000, 2048)
+            .rom(0x8000, 0xFFFF, vec![])
+            .build();
+        assert_eq!(result.err(), Some(BusError::BackwardsRegion { start: 0x07FF, end: 0x0000 }));
+    }
+
+    #[test]
+    fn test_rom_write_faults() {
+        let mut bus = Bus::builder()
+            .rom(0xC000, 0xFFFF, vec![0xaa; 0x4000])
+            .rom_write_faults(true)
+            .build().unwrap();
+        bus.mem_write(0xC123, 0x00);
+        assert_eq!(bus.take_fault(), Some(0xC123));
+        assert_eq!(bus.mem_read(0xC123), 0xaa);
+    }
+
+    #[test]
+    fn test_rom_file() {
+        let path = std::env::temp_dir().join(format!("hw2_cpu_rom_{}.bin", std::process::id()));
+        fs::write(&path, [0x11, 0x22, 0x33]).unwrap();
+        let mut bus = Bus::builder().rom_file(0xFF00, &path).unwrap().build().unwrap();
+        assert_eq!(bus.mem_read(0xFF00), 0x11);
+        assert_eq!(bus.mem_read(0xFF02), 0x33);
+        // nothing mapped past the end of the file
+        assert_eq!(bus.mem_read(0xFF03), 0x00);
+
+        assert!(Bus::builder().rom_file(0xFFFE, &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cartridge_prg_rom() {
+        let mut raw = crate::cartridge::test::test_rom(1, 1, 0, 0);
+        // reset vector at the end of the 16 KiB bank
+        raw[16 + 0x3FFC] = 0x00;
+        raw[16 + 0x3FFD] = 0xC0;
+        let rom = Rom::new(&raw).unwrap();
+        let mut bus = Bus::with_rom(&rom).unwrap();
+        assert_eq!(bus.mem_read_u16(0xFFFC), 0xC000);
+        // mirrored into $8000-$BFFF
+        assert_eq!(bus.mem_read_u16(0xBFFC), 0xC000);
+        bus.mem_write(0x8000, 0x12);
+        assert_eq!(bus.mem_read(0x8000), 0x00);
+        assert_eq!(bus.mapper().unwrap().borrow_mut().ppu_read(0x0000), 0x80);
+    }
+
+    #[test]
+    fn test_cartridge_bank_switching() {
+        // UxROM with 4 banks, bank number in every byte of the bank
+        let rom = Rom::new(&crate::cartridge::test::test_rom(4, 0, 0x20, 0)).unwrap();
+        let mut bus = Bus::with_rom(&rom).unwrap();
+        assert_eq!(bus.mem_read(0x8000), 0);
+        assert_eq!(bus.mem_read(0xC000), 3);
+        bus.mem_write(0x8000, 2);
+        assert_eq!(bus.mem_read(0x8000), 2);
+        assert_eq!(bus.mem_read(0xC000), 3);
+
+        let rom = Rom::new(&crate::cartridge::test::test_rom(1, 1, 0x40, 0)).unwrap();
+        assert!(matches!(Bus::with_rom(&rom), Err(RomError::UnsupportedMapper(4))));
+    }
+
+    #[test]
+    fn test_prg_ram_save_file() {
+        let path = std::env::temp_dir().join(format!("hw2_cpu_save_{}.sav", std::process::id()));
+        let rom = Rom::new(&crate::cartridge::test::test_rom(1, 1, 0b10, 0)).unwrap();
+        let mut bus = Bus::with_rom(&rom).unwrap();
+        // no save yet, RAM starts blank
+        assert_eq!(bus.load_prg_ram(&path).unwrap(), None);
+        assert_eq!(bus.mem_read(0x6000), 0);
+        bus.mem_write(0x6000, 0x12);
+        bus.mem_write(0x7FFF, 0x34);
+        bus.save_prg_ram(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 8192);
+
+        let mut bus = Bus::with_rom(&rom).unwrap();
+        assert_eq!(bus.load_prg_ram(&path).unwrap(), None);
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+        assert_eq!(bus.mem_read(0x7FFF), 0x34);
+
+        // a short save from some other emulator still loads, but gets reported
+        fs::write(&path, [0x56, 0x78]).unwrap();
+        let mut bus = Bus::with_rom(&rom).unwrap();
+        assert_eq!(bus.load_prg_ram(&path).unwrap(), Some((2, 8192)));
+        assert_eq!(bus.mem_read(0x6001), 0x78);
+        assert_eq!(bus.mem_read(0x6002), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_device_dispatch() {
+        let writes = Rc::new(RefCell::new(vec![]));
+        let mut bus = Bus::builder()
+            .device(0xD000, 0xD00F, Recorder { writes: writes.clone() })
+            .ram(0x0000, 0xFFFF, 0x10000)
+            .build().unwrap();
+        bus.mem_write(0xD003, 0x42);
+        bus.mem_write(0xD010, 0x43);
+        // device gets the offset into its region, RAM answers everything else
+        assert_eq!(*writes.borrow(), vec![(0x03, 0x42)]);
+        assert_eq!(bus.mem_read(0xD005), 0x05);
+        assert_eq!(bus.mem_read(0xD010), 0x43);
+    }
+
+    #[test]
+    fn test_ppu_registers() {
+        let mut bus = Bus::new();
+        // PPUADDR through a mirror, then PPUDATA
+        bus.mem_write(0x3FFE, 0x21);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x42);
+        assert_eq!(bus.ppu().unwrap().borrow().vram[0x0100], 0x42);
+        assert_eq!(bus.take_fault(), None);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = Bus::new();
+        for i in 0..=0xFF {
+            bus.mem_write(0x0300 + i, i as u8);
+        }
+        // OAMADDR 4, the copy wraps around to the start of OAM
+        bus.mem_write(0x2003, 0x04);
+        bus.mem_write(0x4014, 0x03);
+        let ppu = bus.ppu().unwrap();
+        assert_eq!(ppu.borrow().oam[4], 0x00);
+        assert_eq!(ppu.borrow().oam[0xFF], 0xFB);
+        assert_eq!(ppu.borrow().oam[0x03], 0xFF);
+        assert_eq!(bus.take_stall_cycles(), 0);
+        // stall is charged once the instruction's cycles are in
+        bus.tick(4);
+        assert_eq!(bus.take_stall_cycles(), 513);
+        assert_eq!(bus.take_stall_cycles(), 0);
+    }
+
+    #[test]
+    fn test_joypads() {
+        let mut bus = Bus::new();
+        bus.set_buttons(0, JoypadButton::A | JoypadButton::SELECT);
+        bus.set_buttons(1, JoypadButton::B);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        let player_1: Vec<u8> = (0..3).map(|_| bus.mem_read(0x4016) & 1).collect();
+        let player_2: Vec<u8> = (0..3).map(|_| bus.mem_read(0x4017) & 1).collect();
+        assert_eq!(player_1, vec![1, 0, 1]);
+        assert_eq!(player_2, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn test_apu_registers() {
+        let mut bus = Bus::new();
+        // enable pulse 1 and load its length counter
+        bus.mem_write(0x4015, 0x01);
+        bus.mem_write(0x4003, 0x08);
+        assert_eq!(bus.mem_read(0x4015) & 0x01, 0x01);
+        // $4017 writes reach the frame counter, reads still come from controller 2
+        bus.mem_write(0x4017, 0x40);
+        bus.tick(29830);
+        assert!(!bus.irq());
+        bus.mem_write(0x4017, 0x00);
+        bus.tick(29830);
+        assert!(bus.irq());
+        assert_eq!(bus.mem_read(0x4017), 0x40);
+        assert_eq!(bus.take_fault(), None);
+    }
+
+    #[test]
+    fn test_unsupported_region_faults() {
+        let mut bus = Bus::builder().unsupported(0x4000, 0x4017).build().unwrap();
+        assert_eq!(bus.mem_read(0x4016), 0);
+        assert_eq!(bus.take_fault(), Some(0x4016));
+        assert_eq!(bus.take_fault(), None);
+        // unmapped space is open bus, not a fault
+        assert_eq!(bus.mem_read(0x5000), 0);
+        assert_eq!(bus.take_fault(), None);
+    }
+}
@@ -0,0 +1,272 @@
+// iNES and NES 2.0 cartridge images
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // "NES" followed by MS-DOS end of file
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
+
+// how the PPU nametables are mirrored, hard wired on most boards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    // selected at runtime by boards like MMC1
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    // empty when the board uses CHR-RAM instead
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    // NES 2.0 only, 0 for iNES images
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    // PRG-RAM at $6000-$7FFF is battery backed
+    pub battery: bool,
+    // 512 bytes meant to be loaded at $7000 before the game starts
+    pub trainer: Option<Vec<u8>>,
+    pub prg_ram_size: usize,
+    pub nes2: bool,
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // file does not start with the iNES tag
+    NotINes,
+    // file ends before the sizes in the header say it should
+    Truncated { expected: usize, found: usize },
+    // board we have no mapper for
+    UnsupportedMapper(u16),
+    // header has no PRG-ROM for the cpu to run
+    NoPrgRom,
+    // header sizes add up to more bytes than can be addressed
+    TooLarge,
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "could not read ROM: {}", err),
+            RomError::NotINes => write!(f, "file is not in iNES format"),
+            RomError::Truncated { expected, found } => {
+                write!(f, "ROM is truncated: header describes {} bytes, file has {}", expected, found)
+            }
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+            RomError::NoPrgRom => write!(f, "ROM has no PRG-ROM"),
+            RomError::TooLarge => write!(f, "ROM header describes an impossibly large image"),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+// NES 2.0 sizes either count 16/8 KiB pages or, when the MSB nibble is $F,
+// encode 2^exponent * (multiplier * 2 + 1) bytes. None when that doesn't fit in a usize
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Option<usize> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize;
+        1usize.checked_shl(exponent)?.checked_mul(multiplier * 2 + 1)
+    } else {
+        (((msb as usize) << 8) | lsb as usize).checked_mul(page_size)
+    }
+}
+
+// NES 2.0 RAM sizes are shift counts: 0 means none, otherwise 64 << n bytes
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err(RomError::NotINes);
+        }
+
+        let flags_6 = raw[6];
+        let flags_7 = raw[7];
+        let nes2 = flags_7 & 0b0000_1100 == 0b0000_1000;
+
+        let mirroring = if flags_6 & 0b1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags_6 & 0b1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags_6 & 0b10 != 0;
+        let has_trainer = flags_6 & 0b100 != 0;
+
+        let mut mapper = ((flags_7 & 0xF0) | (flags_6 >> 4)) as u16;
+        let mut submapper = 0;
+        let prg_rom_size;
+        let chr_rom_size;
+        let prg_ram_size;
+        if nes2 {
+            mapper |= ((raw[8] & 0x0F) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_rom_size = nes2_rom_size(raw[4], raw[9] & 0x0F, PRG_ROM_PAGE_SIZE).ok_or(RomError::TooLarge)?;
+            chr_rom_size = nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE).ok_or(RomError::TooLarge)?;
+            // volatile and battery backed PRG-RAM
+            prg_ram_size = nes2_ram_size(raw[10] & 0x0F) + nes2_ram_size(raw[10] >> 4);
+        } else {
+            // old dumps often have garbage in bytes 7-15, in which case only the low mapper nibble is trusted
+            if raw[12..16].iter().any(|&byte| byte != 0) {
+                mapper &= 0x0F;
+            }
+            prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+            chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+            // 0 means 8 KiB for compatibility
+            prg_ram_size = raw[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+        }
+
+        // the mappers mirror PRG-ROM to fill the cpu's view, so there has to be some
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+
+        let trainer_start = HEADER_SIZE;
+        let prg_rom_start = trainer_start + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start.checked_add(prg_rom_size).ok_or(RomError::TooLarge)?;
+        let expected = chr_rom_start.checked_add(chr_rom_size).ok_or(RomError::TooLarge)?;
+        if raw.len() < expected {
+            return Err(RomError::Truncated { expected, found: raw.len() });
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..expected].to_vec(),
+            mapper,
+            submapper,
+            mirroring,
+            battery,
+            trainer: if has_trainer { Some(raw[trainer_start..prg_rom_start].to_vec()) } else { None },
+            prg_ram_size,
+            nes2,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Rom, RomError> {
+        let raw = fs::read(path)?;
+        Rom::new(&raw)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // iNES image with recognisable PRG/CHR contents
+    pub fn test_rom(prg_pages: u8, chr_pages: u8, flags_6: u8, flags_7: u8) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, flags_6, flags_7, 0, 0, 0, 0, 0, 0, 0, 0];
+        if flags_6 & 0b100 != 0 {
+            raw.extend(vec![0x77; TRAINER_SIZE]);
+        }
+        for page in 0..prg_pages {
+            raw.extend(vec![page; PRG_ROM_PAGE_SIZE]);
+        }
+        for page in 0..chr_pages {
+            raw.extend(vec![0x80 | page; CHR_ROM_PAGE_SIZE]);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let rom = Rom::new(&test_rom(2, 1, 0x31, 0x00)).unwrap();
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_rom[PRG_ROM_PAGE_SIZE], 1);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom[0], 0x80);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+        assert!(rom.trainer.is_none());
+        assert_eq!(rom.prg_ram_size, PRG_RAM_PAGE_SIZE);
+        assert!(!rom.nes2);
+    }
+
+    #[test]
+    fn test_trainer_and_battery() {
+        let rom = Rom::new(&test_rom(1, 0, 0b0110, 0x10)).unwrap();
+        assert_eq!(rom.mapper, 0x10);
+        assert!(rom.battery);
+        assert_eq!(rom.trainer, Some(vec![0x77; TRAINER_SIZE]));
+        assert_eq!(rom.prg_rom[0], 0);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.mirroring, Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let mut raw = test_rom(1, 1, 0x18, 0x48);
+        raw[8] = 0x21;    // submapper 2, mapper bits 8-11 = 1
+        raw[10] = 0x70;   // 8 KiB battery backed PRG-RAM
+        let rom = Rom::new(&raw).unwrap();
+        assert!(rom.nes2);
+        assert_eq!(rom.mapper, 0x141);
+        assert_eq!(rom.submapper, 2);
+        assert_eq!(rom.mirroring, Mirroring::FourScreen);
+        assert_eq!(rom.prg_ram_size, 8192);
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let mut raw = test_rom(1, 1, 0, 0);
+        raw[0] = b'X';
+        assert!(matches!(Rom::new(&raw), Err(RomError::NotINes)));
+        assert!(matches!(Rom::new(&[0x4E, 0x45, 0x53]), Err(RomError::NotINes)));
+
+        let raw = test_rom(2, 1, 0, 0);
+        let truncated = &raw[..raw.len() - 1];
+        assert!(matches!(
+            Rom::new(truncated),
+            Err(RomError::Truncated { expected, found }) if expected == raw.len() && found == raw.len() - 1
+        ));
+
+        assert!(matches!(Rom::new(&test_rom(0, 1, 0, 0)), Err(RomError::NoPrgRom)));
+        let mut raw = test_rom(1, 1, 0, 0x08);
+        raw[4] = 0;
+        assert!(matches!(Rom::new(&raw), Err(RomError::NoPrgRom)));
+    }
+
+    #[test]
+    fn test_nes2_size_overflow() {
+        // exponent-multiplier sizes: 2^63 * 7 PRG-ROM bytes
+        let mut raw = test_rom(1, 1, 0, 0x08);
+        raw[4] = 0xFF;
+        raw[9] = 0x0F;
+        assert!(matches!(Rom::new(&raw), Err(RomError::TooLarge)));
+
+        // each size fits on its own but not together: 2^63 * 1 bytes of both PRG and CHR
+        raw[4] = 0xFC;
+        raw[5] = 0xFC;
+        raw[9] = 0xFF;
+        assert!(matches!(Rom::new(&raw), Err(RomError::TooLarge)));
+
+        // page counts can only overflow with pages bigger than the real ones
+        assert_eq!(nes2_rom_size(0x00, 0x0E, usize::MAX / 0x0E00 + 1), None);
+        assert_eq!(nes2_rom_size(0x02, 0x00, PRG_ROM_PAGE_SIZE), Some(2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(nes2_rom_size(0x0B, 0x0F, 1), Some(4 * 7));
+    }
+}
@@ -1,6 +1,7 @@
 //imports
 use crate::opcodes;
 use std::collections::HashMap;
+use std::fmt;
 use crate::bus::Bus;
 
 // map bitflags as const masks to set easily
@@ -21,7 +22,14 @@ bitflags! {
 const STACK: u16 = 0x0100;
 const STACK_RST: u8 = 0xFD;
 
-pub struct CPU {
+// interrupt vectors
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+// cycles taken to push state and jump through a vector
+const INTERRUPT_CYCLES: u64 = 7;
+
+// the cpu core works against any memory implementing Mem, the NES style Bus by default
+pub struct CPU<M: Mem = Bus> {
     // accumulator
     pub reg_a: u8,
     // registers x and y
@@ -33,10 +41,23 @@ pub struct CPU {
     pub program_counter: u16,
     pub stack_ptr: u8,
     // add bus
-    pub bus: Bus,
+    pub bus: M,
+    pub brk_mode: BrkMode,
+    // BCD arithmetic in ADC/SBC when D is set, the NES 2A03 has it cut out
+    pub has_decimal_mode: bool,
+    // undocumented opcodes execute, otherwise they are illegal opcode errors
+    pub has_unofficial_opcodes: bool,
+    // total clock cycles executed since power on
+    cycles: u64,
+    // effective address computed by the instruction being stepped
+    last_operand: Option<u16>,
+    // interrupt inputs: nmi is latched on the edge, irq is a level held by the device
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_line: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 // enum of all of the 6502 CPU adressing modes.
 pub enum AddressingMode {
@@ -49,34 +70,123 @@ pub enum AddressingMode {
     ABY,
     IZX,
     IZY,
+    IND,
     NoneAddressing,
 }
 
+// true when two addresses sit on different 256 byte pages
+fn page_crossed(addr1: u16, addr2: u16) -> bool {
+    addr1 & 0xFF00 != addr2 & 0xFF00
+}
+
+// what the BRK instruction does when executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrkMode {
+    // real 6502 behaviour: software interrupt through the IRQ/BRK vector
+    Interrupt,
+    // stop the emulator, handy for test programs that end in BRK
+    Halt,
+}
+
+// why the cpu stopped running without an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // BRK executed while in BrkMode::Halt
+    Break,
+}
+
+// summary of one executed instruction, as returned by CPU::step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    // address the instruction was fetched from
+    pub pc: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    // effective address of the operand, for instructions that have one
+    pub operand_address: Option<u16>,
+    // cycles consumed, including interrupt entry and page/branch penalties
+    pub cycles: u64,
+    // set when the instruction stopped the cpu
+    pub stop: Option<StopReason>,
+}
+
+// errors that stop execution, always reported with the pc of the faulting instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    // byte at pc does not decode to an instruction the cpu implements
+    IllegalOpcode { pc: u16, opcode: u8 },
+    // instruction asked for an operand address in a mode that does not have one
+    UnsupportedAddressing { pc: u16, mode: AddressingMode },
+    // the bus rejected an access to addr, e.g. unemulated hardware or a write to ROM
+    BusFault { pc: u16, addr: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode {:#04x} at {:#06x}", opcode, pc)
+            }
+            CpuError::UnsupportedAddressing { pc, mode } => {
+                write!(f, "addressing mode {:?} is not supported (at {:#06x})", mode, pc)
+            }
+            CpuError::BusFault { pc, addr } => {
+                write!(f, "bus fault accessing {:#06x} (at {:#06x})", addr, pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
 // implement memory functions as trait rather than directly into CPU class
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
 
     fn mem_write(&mut self, addr: u16, data: u8);
 
     // little endian support ==========================================
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let low_order = self.mem_read(pos) as u16;
-        let high_order = self.mem_read(pos + 1) as u16;
+        let high_order = self.mem_read(pos.wrapping_add(1)) as u16;
         // chain them together
-        (high_order << 8) | (low_order as u16)
+        (high_order << 8) | low_order
     }
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let high_order = (data >> 8) as u8;
         let low_order = (data & 0xFF) as u8; // using masking
         self.mem_write(pos, low_order);
-        self.mem_write(pos+1, high_order);
+        self.mem_write(pos.wrapping_add(1), high_order);
+    }
+
+    // address of the last access the memory refused since the previous call, if any
+    fn take_fault(&mut self) -> Option<u16> {
+        None
+    }
+
+    // let the rest of the system run for the cycles the last instruction took
+    fn tick(&mut self, _cycles: u64) {}
+
+    // whether a device raised NMI since the previous call
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+
+    // whether a device is holding the IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // cycles the cpu has to sit out because a device took over the bus, e.g. for DMA
+    fn take_stall_cycles(&mut self) -> u64 {
+        0
     }
 }
 
-impl Mem for CPU {
+impl<M: Mem> Mem for CPU<M> {
     // memory related functions
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
         //self.memory[addr as usize]
     }
@@ -87,7 +197,7 @@ impl Mem for CPU {
     }
 
     
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         self.bus.mem_read_u16(pos)
     }
 
@@ -97,8 +207,8 @@ impl Mem for CPU {
     
 }
 
-impl CPU {
-    pub fn new(bus: Bus) -> Self {
+impl<M: Mem> CPU<M> {
+    pub fn new(bus: M) -> Self {
         CPU {
             reg_a: 0,
             reg_x: 0,
@@ -106,115 +216,209 @@ impl CPU {
             status: Flags::from_bits_truncate(0b100100),
             program_counter: 0,
             stack_ptr: STACK_RST,
-            bus: bus,   
+            bus,
+            brk_mode: BrkMode::Interrupt,
+            has_decimal_mode: true,
+            has_unofficial_opcodes: true,
+            cycles: 0,
+            last_operand: None,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
         }
     }
 
+    // number of clock cycles the cpu has run for
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // interrupt lines ===================================================
+    // NMI is edge triggered: asserting the line latches one interrupt, holding it does nothing more
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = asserted;
+    }
+
+    // pulse the NMI line, for devices that signal a single event
+    pub fn trigger_nmi(&mut self) {
+        self.set_nmi(true);
+        self.set_nmi(false);
+    }
+
+    // IRQ is level triggered: it fires between instructions for as long as the line is held
+    // and the interrupt disable flag is clear
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    // service a pending interrupt, NMI taking priority over IRQ
+    fn poll_interrupts(&mut self) {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+        } else if (self.irq_line || self.bus.irq()) && !self.status.contains(Flags::INTERRUPT) {
+            self.interrupt(IRQ_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+        }
+    }
+
+    // push pc and status, then jump through the vector. The B flag is only set in the pushed
+    // status when the interrupt came from a BRK instruction
+    fn interrupt(&mut self, vector: u16, break_flag: bool) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags_to_push = self.status;
+        flags_to_push.set(Flags::BREAK, break_flag);
+        flags_to_push.insert(Flags::BREAK2);
+        self.stack_push(flags_to_push.bits);
+
+        self.status.insert(Flags::INTERRUPT);
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
     // Adressing Mode handling==============================================
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    // returns the operand address along with whether indexing crossed a page boundary,
+    // which costs read instructions an extra cycle
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> Result<(u16, bool), CpuError> {
+        let operand = self.operand_address(mode)?;
+        self.last_operand = Some(operand.0);
+        Ok(operand)
+    }
+
+    fn operand_address(&mut self, mode: &AddressingMode) -> Result<(u16, bool), CpuError> {
         match mode {
             // Immediate
             AddressingMode::IMM => {
-                self.program_counter
+                Ok((self.program_counter, false))
             }
             // Zero-Page Adressing using our endian memread function
             AddressingMode::ZP0 => {
-                self.mem_read(self.program_counter) as u16
+                Ok((self.mem_read(self.program_counter) as u16, false))
             }
             // Zero-Page with X offset
             AddressingMode::ZPX => {
                 let position = self.mem_read(self.program_counter);
-                let ret = position.wrapping_add(self.reg_x) as u16;
-                ret
+                Ok((position.wrapping_add(self.reg_x) as u16, false))
             }
             // Zero-Page with Y offset
             AddressingMode::ZPY => {
                 let position = self.mem_read(self.program_counter);
-                let ret = position.wrapping_add(self.reg_y) as u16;
-                ret
+                Ok((position.wrapping_add(self.reg_y) as u16, false))
             }
             // Absolute
             AddressingMode::ABS => {
-                self.mem_read_u16(self.program_counter)
+                Ok((self.mem_read_u16(self.program_counter), false))
             }
             // Absolute with X offset
             AddressingMode::ABX => {
                 let position = self.mem_read_u16(self.program_counter);
                 let ret = position.wrapping_add(self.reg_x as u16);
-                ret
+                Ok((ret, page_crossed(position, ret)))
             }
             // Absolute with Y offset
             AddressingMode::ABY => {
                 let position = self.mem_read_u16(self.program_counter);
                 let ret = position.wrapping_add(self.reg_y as u16);
-                ret
+                Ok((ret, page_crossed(position, ret)))
             }
             // Indirect off zero page with X offset
             AddressingMode::IZX => {
                 let base = self.mem_read(self.program_counter);
-                let ptr: u8 = (base as u8).wrapping_add(self.reg_x);
+                let ptr: u8 = base.wrapping_add(self.reg_x);
 
                 let low_order = self.mem_read(ptr as u16);
                 let high_order = self.mem_read(ptr.wrapping_add(1) as u16);
 
-                (high_order as u16) << 8 | (low_order as u16)
+                Ok(((high_order as u16) << 8 | (low_order as u16), false))
             }
-            // Indirect off zero page with Y offset
+            // Indirect off zero page, Y offset added to the dereferenced address
             AddressingMode::IZY => {
                 let base = self.mem_read(self.program_counter);
-                let ptr: u8 = (base as u8).wrapping_add(self.reg_y);
 
-                let low_order = self.mem_read(ptr as u16);
-                let high_order = self.mem_read(ptr.wrapping_add(1) as u16);
+                let low_order = self.mem_read(base as u16);
+                let high_order = self.mem_read(base.wrapping_add(1) as u16);
+                let deref = (high_order as u16) << 8 | (low_order as u16);
 
-                (high_order as u16) << 8 | (low_order as u16)
+                let ret = deref.wrapping_add(self.reg_y as u16);
+                Ok((ret, page_crossed(deref, ret)))
+            }
+            // Indirect, only used by JMP
+            // page bug: if the pointer sits at the end of a page, the high byte is read from the start of that same page
+            AddressingMode::IND => {
+                let ptr = self.mem_read_u16(self.program_counter);
+                let ind_reference = if ptr & 0x00FF == 0x00FF {
+                    let low_order = self.mem_read(ptr);
+                    let high_order = self.mem_read(ptr & 0xFF00);
+                    (high_order as u16) << 8 | (low_order as u16)
+                } else {
+                    self.mem_read_u16(ptr)
+                };
+                Ok((ind_reference, false))
             }
             // catch invalid addressing
             AddressingMode::NoneAddressing => {
-                panic!("Addressing mode {:?} is not supported.", mode)
+                Err(CpuError::UnsupportedAddressing { pc: self.program_counter.wrapping_sub(1), mode: *mode })
             }
         }
     }
 
+    // read instructions take one extra cycle when indexing crosses into the next page
+    fn get_read_address(&mut self, mode: &AddressingMode) -> Result<u16, CpuError> {
+        let (addr, crossed) = self.get_operand_address(mode)?;
+        if crossed {
+            self.cycles += 1;
+        }
+        Ok(addr)
+    }
+
     // opcode implementations========================================
     // memory
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn lda(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let val = self.mem_read(addr);
 
         self.reg_a = val;
         self.set_flags(self.reg_a);
+        Ok(())
     }
 
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ldx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let val = self.mem_read(addr);
 
         self.reg_x = val;
         self.set_flags(self.reg_x);
+        Ok(())
     }
 
-    fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ldy(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let val = self.mem_read(addr);
 
         self.reg_y = val;
         self.set_flags(self.reg_y);
+        Ok(())
     }
 
-    fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn sta(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         self.mem_write(addr, self.reg_a);
+        Ok(())
     }
 
-    fn stx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn stx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         self.mem_write(addr, self.reg_x);
+        Ok(())
     }
 
-    fn sty(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn sty(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         self.mem_write(addr, self.reg_y);
+        Ok(())
     }
 
     // helper function to set value of reg a
@@ -247,37 +451,107 @@ impl CPU {
         self.set_reg_a(val);
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    // decimal mode only applies when the D flag is set and the chip has the BCD circuitry
+    fn decimal_mode(&self) -> bool {
+        self.status.contains(Flags::DECIMAL) && self.has_decimal_mode
+    }
+
+    // NMOS decimal addition, following the sequences in Bruce Clark's "Decimal Mode" tutorial.
+    // Z comes from the binary sum, N and V from the sum before the high digit is adjusted
+    fn decimal_addition_reg_a(&mut self, data: u8) {
+        let carry = if self.status.contains(Flags::CARRY) { 1 } else { 0 };
+
+        let binary = self.reg_a.wrapping_add(data).wrapping_add(carry as u8);
+
+        let mut low_digit = (self.reg_a & 0x0F) as i16 + (data & 0x0F) as i16 + carry;
+        if low_digit >= 0x0A {
+            low_digit = ((low_digit + 0x06) & 0x0F) + 0x10;
+        }
+        let mut temp = (self.reg_a & 0xF0) as i16 + (data & 0xF0) as i16 + low_digit;
+        let signed = (self.reg_a & 0xF0) as i8 as i16 + (data & 0xF0) as i8 as i16 + low_digit;
+
+        self.status.set(Flags::NEGATIVE, temp & 0x80 != 0);
+        self.status.set(Flags::OVERFLOW, !(-128..=127).contains(&signed));
+
+        if temp >= 0xA0 {
+            temp += 0x60;
+        }
+        self.status.set(Flags::CARRY, temp >= 0x100);
+        self.status.set(Flags::ZERO, binary == 0);
+        self.reg_a = temp as u8;
+    }
+
+    // NMOS decimal subtraction. All flags match binary mode, only the result is adjusted
+    fn decimal_subtraction_reg_a(&mut self, data: u8) {
+        let borrow = if self.status.contains(Flags::CARRY) { 0 } else { 1 };
+
+        let mut low_digit = (self.reg_a & 0x0F) as i16 - (data & 0x0F) as i16 - borrow;
+        if low_digit < 0 {
+            low_digit = ((low_digit - 0x06) & 0x0F) - 0x10;
+        }
+        let mut temp = (self.reg_a & 0xF0) as i16 - (data & 0xF0) as i16 + low_digit;
+        if temp < 0 {
+            temp -= 0x60;
+        }
+
+        self.addition_reg_a(!data);
+        self.reg_a = temp as u8;
+    }
+
+    // add into the accumulator, honouring decimal mode
+    fn add_reg_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.decimal_addition_reg_a(data);
+        } else {
+            self.addition_reg_a(data);
+        }
+    }
+
+    // subtract from the accumulator, honouring decimal mode
+    fn subtract_reg_a(&mut self, data: u8) {
+        if self.decimal_mode() {
+            self.decimal_subtraction_reg_a(data);
+        } else {
+            // subtraction can utilize addition function
+            // a - b is the same as a + (-b), where -b = !b + 1
+            self.addition_reg_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+            //          cast to 8b int, use wrapping neg to invert, add 1, cast to u8
+        }
+    }
+
+    fn adc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
-        self.addition_reg_a(data);
+        self.add_reg_a(data);
+        Ok(())
     }
 
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn sbc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
-        // subtraction can utilize addition function
-        // a - b is the same as a + (-b), where -b = !b + 1
-        self.addition_reg_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
-        //          cast to 8b int, use wrapping neg to invert, add 1, cast to u8
+        self.subtract_reg_a(data);
+        Ok(())
     }
 
-    fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn and(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
         self.set_reg_a(data & self.reg_a);
+        Ok(())
     }
 
-    fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn eor(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
         self.set_reg_a(data ^ self.reg_a);
+        Ok(())
     }
 
-    fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ora(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
         self.set_reg_a(data | self.reg_a);
+        Ok(())
     }
     
 
@@ -296,10 +570,18 @@ impl CPU {
         self.set_flags(self.reg_x)
     }
 
+    // read-modify-write instructions write the unmodified value back before the result,
+    // which hardware watching the writes can see
+    fn read_modify(&mut self, addr: u16) -> u8 {
+        let data = self.mem_read(addr);
+        self.mem_write(addr, data);
+        data
+    }
+
     // bitshifting
-    fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn asl(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
         // check for carry
         if data >> 7 == 1 {
             // set carry flag
@@ -309,10 +591,10 @@ impl CPU {
         }
 
         // perform the shift
-        data = data << 1;
+        data <<= 1;
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     // shifting regarding accumulator (no data fetches)
@@ -325,13 +607,13 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data << 1;
+        data <<= 1;
         self.set_reg_a(data);
     }
 
-    fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn lsr(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
         // check for carry
         if data & 1 == 1 {
             self.status.insert(Flags::CARRY);
@@ -339,10 +621,10 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data >> 1;
+        data >>= 1;
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     fn lsr_reg_a(&mut self) {
@@ -354,13 +636,13 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data >> 1;
+        data >>= 1;
         self.set_reg_a(data);
     }
 
-    fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn rol(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
         let carry_set = self.status.contains(Flags::CARRY);
         
         // check for carry bit
@@ -370,14 +652,14 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data << 1;
+        data <<= 1;
         if carry_set {
             // set bit 0 to 1
-            data = data | 1;
+            data |= 1;
         }
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     fn rol_reg_a(&mut self) {
@@ -391,16 +673,16 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data << 1;
+        data <<= 1;
         if carry_set {
-            data = data | 1;
+            data |= 1;
         }
         self.set_reg_a(data);
     }
 
-    fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn ror(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
         let carry_set = self.status.contains(Flags::CARRY);
 
         // check for carry bit
@@ -410,13 +692,13 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data >> 1;
+        data >>= 1;
         if carry_set {
-            data = data | 0b1000_0000;
+            data |= 0b1000_0000;
         }
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     fn ror_reg_a(&mut self) {
@@ -430,22 +712,22 @@ impl CPU {
             self.status.remove(Flags::CARRY);
         }
 
-        data = data >> 1;
+        data >>= 1;
         if carry_set {
-            data = data | 0b1000_0000;
+            data |= 0b1000_0000;
         }
         self.set_reg_a(data);
     }
 
-    fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn inc(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
 
         // add 1 using wrapping add
         data = data.wrapping_add(1);
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     fn iny(&mut self) {
@@ -453,15 +735,15 @@ impl CPU {
         self.set_flags(self.reg_y);
     }
 
-    fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn dec(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let mut data = self.read_modify(addr);
 
         //subtract 1 using wrapping sub
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
         self.set_flags(data);
-        data
+        Ok(data)
     }
 
     fn dex(&mut self) {
@@ -474,8 +756,8 @@ impl CPU {
         self.set_flags(self.reg_y);
     }
 
-    fn cmp(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn cmp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let data = self.mem_read(addr);
 
         let val = self.reg_a.wrapping_sub(data);
@@ -488,10 +770,11 @@ impl CPU {
         }
 
         self.set_flags(val);
+        Ok(())
     }
 
-    fn cpx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn cpx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
 
         let val = self.reg_x.wrapping_sub(data);
@@ -504,10 +787,11 @@ impl CPU {
         }
 
         self.set_flags(val);
+        Ok(())
     }
 
-    fn cpy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn cpy(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         let val = self.reg_y.wrapping_sub(data);
 
@@ -519,21 +803,34 @@ impl CPU {
         }
 
         self.set_flags(val);
+        Ok(())
+    }
+
+    fn jmp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.program_counter = addr;
+        Ok(())
     }
 
     // branch with condition to be used for all branch opcodes
+    // taken branches cost one extra cycle, and one more if the target is on another page
     fn branch(&mut self, cond: bool) {
         if cond {
+            self.cycles += 1;
             // calculate location to jump to
             let jump:i8 = self.mem_read(self.program_counter) as i8;
-            let jmp_addr = self.program_counter.wrapping_add(1).wrapping_add(jump as u16);
+            let next_addr = self.program_counter.wrapping_add(1);
+            let jmp_addr = next_addr.wrapping_add(jump as u16);
+            if page_crossed(next_addr, jmp_addr) {
+                self.cycles += 1;
+            }
             // set pc to location
             self.program_counter = jmp_addr;
         }
     }
 
-    fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn bit(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         // apply mask
         let masked = self.reg_a & data;
@@ -547,19 +844,138 @@ impl CPU {
         // BIT opcode sets the value of negative and overflow bits to bit 7 and 6 of mask, respectively
         self.status.set(Flags::NEGATIVE, data & 0b1000_0000 > 0);
         self.status.set(Flags::OVERFLOW, data & 0b0100_0000 > 0);
+        Ok(())
+    }
+
+    // unofficial opcodes, mostly a read-modify-write op chained into an accumulator op
+    fn lax(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let data = self.mem_read(addr);
+        self.set_reg_a(data);
+        self.reg_x = data;
+        Ok(())
+    }
+
+    fn sax(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.reg_a & self.reg_x);
+        Ok(())
+    }
+
+    fn dcp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.dec(mode)?;
+        // compare against A, same as CMP
+        self.status.set(Flags::CARRY, self.reg_a >= data);
+        self.set_flags(self.reg_a.wrapping_sub(data));
+        Ok(())
+    }
+
+    fn isb(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.inc(mode)?;
+        self.subtract_reg_a(data);
+        Ok(())
+    }
+
+    fn slo(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.asl(mode)?;
+        self.set_reg_a(data | self.reg_a);
+        Ok(())
+    }
+
+    fn rla(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.rol(mode)?;
+        self.set_reg_a(data & self.reg_a);
+        Ok(())
+    }
+
+    fn sre(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.lsr(mode)?;
+        self.set_reg_a(data ^ self.reg_a);
+        Ok(())
+    }
+
+    fn rra(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let data = self.ror(mode)?;
+        self.add_reg_a(data);
+        Ok(())
+    }
+
+    fn anc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.and(mode)?;
+        // carry gets a copy of the negative flag
+        self.status.set(Flags::CARRY, self.status.contains(Flags::NEGATIVE));
+        Ok(())
+    }
+
+    fn alr(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.and(mode)?;
+        self.lsr_reg_a();
+        Ok(())
+    }
+
+    // AND then ROR A, with C and V taken from bits 6 and 5 of the result.
+    // Decimal mode fixes up each digit like an ADC would, see "No More Secrets"
+    fn arr(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let data = self.mem_read(addr) & self.reg_a;
+        let carry_in = if self.status.contains(Flags::CARRY) { 0b1000_0000 } else { 0 };
+        let mut result = (data >> 1) | carry_in;
+
+        if self.decimal_mode() {
+            self.status.set(Flags::NEGATIVE, carry_in != 0);
+            self.status.set(Flags::ZERO, result == 0);
+            self.status.set(Flags::OVERFLOW, (data ^ result) & 0b0100_0000 != 0);
+
+            let low_digit = data & 0x0F;
+            let high_digit = data >> 4;
+            if low_digit + (low_digit & 1) > 5 {
+                result = (result & 0xF0) | (result.wrapping_add(6) & 0x0F);
+            }
+            let carry = high_digit + (high_digit & 1) > 5;
+            if carry {
+                result = result.wrapping_add(0x60);
+            }
+            self.status.set(Flags::CARRY, carry);
+            self.reg_a = result;
+        } else {
+            self.set_reg_a(result);
+            let bit_6 = result & 0b0100_0000 != 0;
+            let bit_5 = result & 0b0010_0000 != 0;
+            self.status.set(Flags::CARRY, bit_6);
+            self.status.set(Flags::OVERFLOW, bit_6 ^ bit_5);
+        }
+        Ok(())
+    }
+
+    // X = (A & X) - operand, flags set like CMP
+    fn sbx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let data = self.mem_read(addr);
+        let masked = self.reg_a & self.reg_x;
+
+        self.status.set(Flags::CARRY, masked >= data);
+        self.reg_x = masked.wrapping_sub(data);
+        self.set_flags(self.reg_x);
+        Ok(())
+    }
+
+    fn nop_read(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.mem_read(addr);
+        Ok(())
     }
 
     // stack helper functions
     fn stack_push(&mut self, data: u8) {
         // write to the stack using STACK constant + offset
-        self.mem_write((STACK as u16) + self.stack_ptr as u16, data);
+        self.mem_write(STACK + self.stack_ptr as u16, data);
         self.stack_ptr = self.stack_ptr.wrapping_sub(1);
     }
 
     fn stack_pop(&mut self) -> u8 {
         // read from stack using STACK const + offset
         self.stack_ptr = self.stack_ptr.wrapping_add(1);
-        self.mem_read((STACK as u16) + self.stack_ptr as u16)
+        self.mem_read(STACK + self.stack_ptr as u16)
     }
 
     fn stack_push_u16(&mut self, data: u16) {
@@ -575,8 +991,7 @@ impl CPU {
         let low_order = self.stack_pop() as u16;
         let high_order = self.stack_pop() as u16;
         // combine
-        let val = high_order << 8 | low_order;
-        val
+        high_order << 8 | low_order
     }
 
     // stack opcodes
@@ -592,7 +1007,7 @@ impl CPU {
     }
 
     fn php(&mut self) {
-        let mut flags_to_push = self.status.clone();
+        let mut flags_to_push = self.status;
         // php opcode sets B flags to 1
         flags_to_push.insert(Flags::BREAK);
         flags_to_push.insert(Flags::BREAK2);
@@ -617,7 +1032,9 @@ impl CPU {
     }
     //==================================================================
 
-    pub fn load_run(&mut self, program: Vec<u8>) {
+    // load, reset and run a test program. BRK halts the emulator here instead of trapping
+    pub fn load_run(&mut self, program: Vec<u8>) -> Result<StopReason, CpuError> {
+        self.brk_mode = BrkMode::Halt;
         self.load(program);
         self.reset();
         self.run()
@@ -639,306 +1056,419 @@ impl CPU {
         self.status = Flags::from_bits_truncate(0b100100);
         self.program_counter = self.mem_read_u16(0xFFFC);
         self.stack_ptr = STACK_RST;
+        self.nmi_pending = false;
         // self.memory = [0; 0xFFFF];
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn run(&mut self) -> Result<StopReason, CpuError> {
+        self.run_with_callback(|_| {})
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    // run until the cpu stops, calling back after every instruction
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<StopReason, CpuError>
     where 
-        F: FnMut(&mut CPU) 
+        F: FnMut(&mut CPU<M>) 
     {
+        loop {
+            if let Some(reason) = self.step()?.stop {
+                return Ok(reason);
+            }
+            callback(self);
+        }
+    }
+
+    // execute exactly one instruction, servicing a pending interrupt first
+    pub fn step(&mut self) -> Result<Step, CpuError> {
         // hashmap of opcodes
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
+
+        let start_cycles = self.cycles;
+        self.last_operand = None;
+        // hardware interrupts are only sampled between instructions
+        self.poll_interrupts();
+
+        // read from memory
+        let code = self.mem_read(self.program_counter);
+        // increment program counter
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let pc_state = self.program_counter;
+        let instr_pc = pc_state.wrapping_sub(1);
+        let opcode = match opcodes.get(&code) {
+            Some(opcode) => opcode,
+            None => return Err(CpuError::IllegalOpcode { pc: instr_pc, opcode: code }),
+        };
+        if opcode.unofficial && !self.has_unofficial_opcodes {
+            return Err(CpuError::IllegalOpcode { pc: instr_pc, opcode: code });
+        }
+        // base cycle count, page crossing and branch penalties get added by the opcode itself
+        self.cycles += opcode.cycles as u64;
 
-        loop {
-            // read from memory
-            let code = self.mem_read(self.program_counter);
-            // increment program counter
-            self.program_counter = self.program_counter + 1;
-            let pc_state = self.program_counter;
-            let opcode = opcodes.get(&code).unwrap();
-            println!("Instruction Received: {}", opcode.mnemonic);
-
-            match code {
-                // LDA
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
-                // BRK
-                0x00 => {
-                    return;
-                }
-                //NOP
-                0xea => {
-                    // nothing
-                }
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                }
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                }
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
-                // STX
-                0x86 | 0x96 | 0x8e => {
-                    self.stx(&opcode.mode);
-                }
-                // STY
-                0x84 | 0x94 | 0x8c => {
-                    self.sty(&opcode.mode);
-                }
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-                // SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
-                // TAX
-                0xAA => {
-                    self.tax();
-                }
-                // TAY
-                0xa8 => {
-                    self.tay();
-                }
-                //INX
-                0xe8 => {
-                    self.inx();
-                }
-                // ASL
-                0x0a => {
-                    self.asl_reg_a();
-                }
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
-                // LSR
-                0x4a => {
-                    self.lsr_reg_a();
-                }
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
-                // ROL
-                0x2a => {
-                    self.rol_reg_a();
-                }
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
-                // ROR
-                0x6a => {
-                    self.ror_reg_a();
-                }
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
-                // INY
-                0xc8 => {
-                    self.iny();
-                }
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                }
-                // DEX
-                0xca => {
-                    self.dex();
-                }
-                // DEY
-                0x88 => {
-                    self.dey();
-                }
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.cmp(&opcode.mode);
-                }
-                // CPX
-                0xe0 | 0xe4 | 0xec => {
-                    self.cpx(&opcode.mode);
-                }
-                // CPY
-                0xc0 | 0xc4 | 0xcc => {
-                    self.cpy(&opcode.mode);
-                }
-                // (two types of JMP instructions, 6502 bug emulated)
-                // JMP ABS
-                0x4c => {
-                    let new_addr = self.mem_read_u16(self.program_counter);
-                    self.program_counter = new_addr;
-                }
-                // JMP Indirect
-                // page bug: if JMP crosses page boundary, it jumps to an unexpected location
-                0x6c => {
-                    let new_addr = self.mem_read_u16(self.program_counter);
-                    //------------------------------------------------------
-                    // if the page boundary bug takes place, we manually read the correct reference
-                    // if it does not take place, we can let mem_read_u16 work as expected
-                    let ind_reference = if new_addr & 0x00FF == 0x00FF {
-                        let low_order = self.mem_read(new_addr);
-                        let high_order = self.mem_read(new_addr & 0xFF00);
-                        (high_order as u16) << 8 | (low_order as u16)
-                    } else {
-                        self.mem_read_u16(new_addr)
-                    };
-
-                    self.program_counter = ind_reference;
-                }
-                // JSR
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target;
-                }
-                // RTS
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
-                // RTI
-                // pull status from stack, followed by PC
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(Flags::BREAK);
-                    self.status.remove(Flags::BREAK2);
-
-                    self.program_counter = self.stack_pop_u16();
-                }
-                // BNE
-                0xd0 => {
-                    self.branch(!self.status.contains(Flags::ZERO));
-                }
-                // BVS
-                0x70 => {
-                    self.branch(self.status.contains(Flags::OVERFLOW));
-                }
-                // BVC
-                0x50 => {
-                    self.branch(!self.status.contains(Flags::OVERFLOW));
-                }
-                // BMI
-                0x30 => {
-                    self.branch(self.status.contains(Flags::NEGATIVE));
-                }
-                // BEQ
-                0xF0 => {
-                    self.branch(self.status.contains(Flags::ZERO));
-                }
-                // BCS
-                0xB0 => {
-                    self.branch(self.status.contains(Flags::CARRY));
-                }
-                // BCC
-                0x90 => {
-                    self.branch(!self.status.contains(Flags::CARRY));
-                }
-                // BPL
-                0x10 => {
-                    self.branch(!self.status.contains(Flags::NEGATIVE));
-                }
-                // BIT
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
-                // TSX
-                0xba => {
-                    self.reg_x = self.stack_ptr;
-                    self.set_flags(self.reg_x);
-                }
-                // TXA
-                0x8a => {
-                    self.reg_a = self.reg_x;
-                    self.set_flags(self.reg_a);
-                }
-                // TXS
-                0x9a => {
-                    self.stack_ptr = self.reg_x;
-                }
-                // TYA
-                0x98 => {
-                    self.reg_a = self.reg_y;
-                    self.set_flags(self.reg_a);
-                }
-                // CLD
-                0xd8 => {
-                    self.status.remove(Flags::DECIMAL);
-                }
-                // CLI
-                0x58 => {
-                    self.status.remove(Flags::INTERRUPT);
-                }
-                // CLV
-                0xb8 => {
-                    self.status.remove(Flags::OVERFLOW);
-                }
-                // CLC
-                0x18 => {
-                    self.status.remove(Flags::CARRY);
-                }
-                // SEC
-                0x38 => {
-                    self.status.insert(Flags::CARRY);
-                }
-                // SEI
-                0x78 => {
-                    self.status.insert(Flags::INTERRUPT);
-                }
-                // SED
-                0xf8 => {
-                    self.status.insert(Flags::DECIMAL);
-                }
-                // PHA
-                0x48 => {
-                    self.stack_push(self.reg_a);
-                }
-                // PLA
-                0x68 => {
-                    self.pla();
-                }
-                // PHP
-                0x08 => {
-                    self.php();
-                }
-                // PLP
-                0x28 => {
-                    self.plp();
+        match code {
+            // LDA
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode)?;
+            }
+            // BRK
+            // the byte after BRK is padding, so the return address skips it
+            0x00 => {
+                if self.brk_mode == BrkMode::Halt {
+                    return Ok(self.step_info(instr_pc, opcode, start_cycles, Some(StopReason::Break)));
                 }
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.interrupt(IRQ_VECTOR, true);
+            }
+            //NOP
+            0xea => {
+                // nothing
+            }
+            // LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode)?;
+            }
+            // LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode)?;
+            }
+            // STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode)?;
+            }
+            // STX
+            0x86 | 0x96 | 0x8e => {
+                self.stx(&opcode.mode)?;
+            }
+            // STY
+            0x84 | 0x94 | 0x8c => {
+                self.sty(&opcode.mode)?;
+            }
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode)?;
+            }
+            // SBC
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode)?;
+            }
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode)?;
+            }
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode)?;
+            }
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode)?;
+            }
+            // TAX
+            0xAA => {
+                self.tax();
+            }
+            // TAY
+            0xa8 => {
+                self.tay();
+            }
+            //INX
+            0xe8 => {
+                self.inx();
+            }
+            // ASL
+            0x0a => {
+                self.asl_reg_a();
+            }
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode)?;
+            }
+            // LSR
+            0x4a => {
+                self.lsr_reg_a();
+            }
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode)?;
+            }
+            // ROL
+            0x2a => {
+                self.rol_reg_a();
+            }
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode)?;
+            }
+            // ROR
+            0x6a => {
+                self.ror_reg_a();
+            }
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode)?;
+            }
+            // INC
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode)?;
+            }
+            // INY
+            0xc8 => {
+                self.iny();
+            }
+            // DEC
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode)?;
+            }
+            // DEX
+            0xca => {
+                self.dex();
+            }
+            // DEY
+            0x88 => {
+                self.dey();
+            }
+            // CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.cmp(&opcode.mode)?;
+            }
+            // CPX
+            0xe0 | 0xe4 | 0xec => {
+                self.cpx(&opcode.mode)?;
+            }
+            // CPY
+            0xc0 | 0xc4 | 0xcc => {
+                self.cpy(&opcode.mode)?;
+            }
+            // JMP ABS and JMP Indirect (6502 page bug emulated in the addressing mode)
+            0x4c | 0x6c => {
+                self.jmp(&opcode.mode)?;
+            }
+            // JSR
+            0x20 => {
+                self.stack_push_u16(self.program_counter.wrapping_add(1));
+                let target = self.mem_read_u16(self.program_counter);
+                self.program_counter = target;
+            }
+            // RTS
+            0x60 => {
+                self.program_counter = self.stack_pop_u16().wrapping_add(1);
+            }
+            // RTI
+            // pull status from stack the same way as PLP, followed by PC
+            0x40 => {
+                self.plp();
 
+                self.program_counter = self.stack_pop_u16();
+            }
+            // BNE
+            0xd0 => {
+                self.branch(!self.status.contains(Flags::ZERO));
+            }
+            // BVS
+            0x70 => {
+                self.branch(self.status.contains(Flags::OVERFLOW));
+            }
+            // BVC
+            0x50 => {
+                self.branch(!self.status.contains(Flags::OVERFLOW));
+            }
+            // BMI
+            0x30 => {
+                self.branch(self.status.contains(Flags::NEGATIVE));
+            }
+            // BEQ
+            0xF0 => {
+                self.branch(self.status.contains(Flags::ZERO));
+            }
+            // BCS
+            0xB0 => {
+                self.branch(self.status.contains(Flags::CARRY));
+            }
+            // BCC
+            0x90 => {
+                self.branch(!self.status.contains(Flags::CARRY));
+            }
+            // BPL
+            0x10 => {
+                self.branch(!self.status.contains(Flags::NEGATIVE));
+            }
+            // BIT
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode)?;
+            }
+            // TSX
+            0xba => {
+                self.reg_x = self.stack_ptr;
+                self.set_flags(self.reg_x);
+            }
+            // TXA
+            0x8a => {
+                self.reg_a = self.reg_x;
+                self.set_flags(self.reg_a);
+            }
+            // TXS
+            0x9a => {
+                self.stack_ptr = self.reg_x;
+            }
+            // TYA
+            0x98 => {
+                self.reg_a = self.reg_y;
+                self.set_flags(self.reg_a);
+            }
+            // CLD
+            0xd8 => {
+                self.status.remove(Flags::DECIMAL);
+            }
+            // CLI
+            0x58 => {
+                self.status.remove(Flags::INTERRUPT);
+            }
+            // CLV
+            0xb8 => {
+                self.status.remove(Flags::OVERFLOW);
+            }
+            // CLC
+            0x18 => {
+                self.status.remove(Flags::CARRY);
+            }
+            // SEC
+            0x38 => {
+                self.status.insert(Flags::CARRY);
+            }
+            // SEI
+            0x78 => {
+                self.status.insert(Flags::INTERRUPT);
+            }
+            // SED
+            0xf8 => {
+                self.status.insert(Flags::DECIMAL);
+            }
+            // PHA
+            0x48 => {
+                self.stack_push(self.reg_a);
+            }
+            // PLA
+            0x68 => {
+                self.pla();
+            }
+            // PHP
+            0x08 => {
+                self.php();
+            }
+            // PLP
+            0x28 => {
+                self.plp();
+            }
 
-                // placeholder for further instructions
-                _ => todo!()
+            /* Unofficial */
+            // LAX
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                self.lax(&opcode.mode)?;
+            }
+            // SAX
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                self.sax(&opcode.mode)?;
+            }
+            // DCP
+            0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xc3 | 0xd3 => {
+                self.dcp(&opcode.mode)?;
+            }
+            // ISB
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
+                self.isb(&opcode.mode)?;
+            }
+            // SLO
+            0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => {
+                self.slo(&opcode.mode)?;
+            }
+            // RLA
+            0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x23 | 0x33 => {
+                self.rla(&opcode.mode)?;
+            }
+            // SRE
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => {
+                self.sre(&opcode.mode)?;
+            }
+            // RRA
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                self.rra(&opcode.mode)?;
+            }
+            // ANC
+            0x0b | 0x2b => {
+                self.anc(&opcode.mode)?;
+            }
+            // ALR
+            0x4b => {
+                self.alr(&opcode.mode)?;
+            }
+            // ARR
+            0x6b => {
+                self.arr(&opcode.mode)?;
             }
+            // SBX
+            0xcb => {
+                self.sbx(&opcode.mode)?;
+            }
+            // SBC (duplicate of 0xe9)
+            0xeb => {
+                self.sbc(&opcode.mode)?;
+            }
+            // NOP, implied
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {
+                // nothing
+            }
+            // NOP, reads its operand and throws it away
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4
+            | 0x0c | 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                self.nop_read(&opcode.mode)?;
+            }
+
+
+            // in the opcode table but not implemented
+            _ => return Err(CpuError::IllegalOpcode { pc: instr_pc, opcode: code }),
+        }
+
+        // surface accesses the bus refused during this instruction
+        if let Some(addr) = self.bus.take_fault() {
+            return Err(CpuError::BusFault { pc: instr_pc, addr });
+        }
+
+        // the rest of the system catches up, an NMI raised meanwhile is taken before the next instruction
+        self.bus.tick(self.cycles - start_cycles);
+        let stall = self.bus.take_stall_cycles();
+        if stall > 0 {
+            self.cycles += stall;
+            self.bus.tick(stall);
+        }
+        if self.bus.poll_nmi() {
+            self.trigger_nmi();
+        }
 
-            // handling of additional cycles needed
-            if pc_state == self.program_counter {
-                self.program_counter += (opcode.length - 1) as u16;
+        // handling of additional cycles needed
+        if pc_state == self.program_counter {
+            self.program_counter = self.program_counter.wrapping_add((opcode.length - 1) as u16);
+        }
+        Ok(self.step_info(instr_pc, opcode, start_cycles, None))
+    }
+
+    fn step_info(&self, pc: u16, opcode: &opcodes::OpCode, start_cycles: u64, stop: Option<StopReason>) -> Step {
+        Step {
+            pc,
+            opcode: opcode.code,
+            mnemonic: opcode.mnemonic,
+            operand_address: self.last_operand,
+            cycles: self.cycles - start_cycles,
+            stop,
+        }
+    }
+}
+
+impl CPU<Bus> {
+    // run until the PPU starts vblank, i.e. the picture for this frame is complete. Returns early
+    // with the reason if the program stops
+    pub fn run_frame(&mut self) -> Result<Option<StopReason>, CpuError> {
+        loop {
+            let step = self.step()?;
+            if step.stop.is_some() {
+                return Ok(step.stop);
+            }
+            if self.bus.take_frame_complete() {
+                return Ok(None);
             }
-            callback(self);
         }
     }
 }
@@ -947,12 +1477,13 @@ impl CPU {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::flat_ram::FlatRam;
     
     #[test]
     fn test_lda_immediate_load() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xa9, 0x05, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xa9, 0x05, 0x00]).unwrap();
         // LDA 0x05
         // BRK
         assert_eq!(cpu.reg_a, 0x05);                // accumulator is set to 0x05
@@ -962,9 +1493,9 @@ mod test {
 
     #[test]
     fn test_lda_zero_flag() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xA9, 0x00, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xA9, 0x00, 0x00]).unwrap();
         // LDA 0x00
         // BRK
         assert_eq!(cpu.reg_a, 0x00);                // accumulator set to 0x00
@@ -973,9 +1504,9 @@ mod test {
 
     #[test]
     fn test_tax_immediate_load() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xA9, 0x05, 0xAA, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xA9, 0x05, 0xAA, 0x00]).unwrap();
         // LDA 0x0A
         // TAX
         // BRK
@@ -986,9 +1517,9 @@ mod test {
 
     #[test]
     fn test_tax_zero_flag() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xAA, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xAA, 0x00]).unwrap();
         // TAX
         // BRK
         assert_eq!(cpu.reg_x, 0x00);                // reg x set to 0x00
@@ -997,9 +1528,9 @@ mod test {
 
     #[test]
     fn test_inx_overflow() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xA9, 0xFF, 0xAA, 0xe8, 0xe8, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xA9, 0xFF, 0xAA, 0xe8, 0xe8, 0x00]).unwrap();
         // LDA 0xFF
         // TAX
         // INX
@@ -1012,9 +1543,9 @@ mod test {
     
     #[test]
     fn test_mini_program() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
-        cpu.load_run(vec![0xA9, 0xC0, 0xAA, 0xE8, 0x00]);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.load_run(vec![0xA9, 0xC0, 0xAA, 0xE8, 0x00]).unwrap();
         // LDA 0xC0
         // TAX
         // INX
@@ -1024,12 +1555,834 @@ mod test {
 
     #[test]
     fn test_lda_imm() {
-        let bus = Bus::new();
-        let mut cpu = CPU::new(bus);
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
         cpu.mem_write(0x10, 0x55);
         // load program
-        cpu.load_run(vec![0xA5, 0x10, 0x00]);
+        cpu.load_run(vec![0xA5, 0x10, 0x00]).unwrap();
         // LDA 0x10 (zero page)
         assert_eq!(cpu.reg_a, 0x55);
     }
+
+    // nothing is mapped behind the reset vector on the bus, so start at the load address by hand
+    fn run_from_load_address(cpu: &mut CPU, program: Vec<u8>) -> Result<StopReason, CpuError> {
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(program);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        cpu.run()
+    }
+
+    #[test]
+    fn test_cycles_base_count() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa9, 0x01, 0xaa, 0x00]).unwrap();
+        // LDA #$01 (2)
+        // TAX      (2)
+        // BRK      (7)
+        assert_eq!(cpu.cycles(), 11);
+    }
+
+    #[test]
+    fn test_cycles_page_cross_penalty() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa2, 0x01, 0xbd, 0x00, 0x02, 0x00]).unwrap();
+        // LDX #$01     (2)
+        // LDA $0200,X  (4, same page)
+        // BRK          (7)
+        assert_eq!(cpu.cycles(), 13);
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa2, 0x01, 0xbd, 0xff, 0x02, 0x00]).unwrap();
+        // LDX #$01     (2)
+        // LDA $02FF,X  (4 +1 page crossed)
+        // BRK          (7)
+        assert_eq!(cpu.cycles(), 14);
+    }
+
+    #[test]
+    fn test_cycles_branch_penalty() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa2, 0x01, 0xf0, 0x02, 0x00]).unwrap();
+        // LDX #$01 (2)
+        // BEQ +2   (2, not taken)
+        // BRK      (7)
+        assert_eq!(cpu.cycles(), 11);
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa2, 0x00, 0xf0, 0x00, 0x00]).unwrap();
+        // LDX #$00 (2)
+        // BEQ +0   (2 +1 taken)
+        // BRK      (7)
+        assert_eq!(cpu.cycles(), 12);
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa2, 0x00, 0xf0, 0xf0, 0x00]).unwrap();
+        // LDX #$00 (2)
+        // BEQ -16  (2 +1 taken +1 new page, lands on a BRK at $05F4)
+        // BRK      (7)
+        assert_eq!(cpu.cycles(), 13);
+        assert_eq!(cpu.program_counter, 0x05F5);
+    }
+
+    // the interrupt vectors point at different handlers, memory there is zero so each is a BRK
+    fn cpu_with_vectors(program: Vec<u8>) -> CPU<FlatRam> {
+        let mut cpu = CPU::new(FlatRam::new());
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(program);
+        cpu.mem_write_u16(0xFFFA, 0x0700);
+        cpu.mem_write_u16(0xFFFE, 0x0800);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_nmi_pushes_state_and_jumps() {
+        let mut cpu = cpu_with_vectors(vec![0xa9, 0x05, 0xea, 0x00]);
+        let mut fired = false;
+        cpu.run_with_callback(|cpu| {
+            if !fired {
+                cpu.trigger_nmi();
+                fired = true;
+            }
+        }).unwrap();
+        // LDA #$05
+        // <NMI> through $FFFA, halting on the BRK at $0700
+        assert_eq!(cpu.program_counter, 0x0701);
+        assert_eq!(cpu.stack_ptr, STACK_RST.wrapping_sub(3));
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);                     // return address high
+        assert_eq!(cpu.mem_read(0x01FC), 0x02);                     // return address low
+        assert_eq!(cpu.mem_read(0x01FB) & Flags::BREAK.bits(), 0);  // B clear for hardware interrupts
+        assert!(cpu.status.contains(Flags::INTERRUPT));
+    }
+
+    #[test]
+    fn test_irq_masked_by_interrupt_flag() {
+        let mut cpu = cpu_with_vectors(vec![0xa9, 0x05, 0xea, 0x00]);
+        cpu.set_irq(true);
+        cpu.run().unwrap();
+        // interrupt flag is set after reset, so the program runs to its own BRK
+        assert_eq!(cpu.program_counter, 0x0604);
+        assert_eq!(cpu.stack_ptr, STACK_RST);
+
+        let mut cpu = cpu_with_vectors(vec![0x58, 0xea, 0x00]);
+        cpu.set_irq(true);
+        cpu.run().unwrap();
+        // CLI
+        // <IRQ> through $FFFE before the NOP, halting on the BRK at $0800
+        assert_eq!(cpu.program_counter, 0x0801);
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);
+        assert_eq!(cpu.mem_read(0x01FC), 0x01);
+        assert_eq!(cpu.mem_read(0x01FB) & Flags::BREAK.bits(), 0);
+    }
+
+    #[test]
+    fn test_irq_level_triggered() {
+        let mut cpu = cpu_with_vectors(vec![0x58, 0xea, 0x00]);
+        // IRQ handler: INX, RTI
+        cpu.mem_write(0x0800, 0xe8);
+        cpu.mem_write(0x0801, 0x40);
+        cpu.set_irq(true);
+
+        // CLI
+        assert_eq!(cpu.step().unwrap().pc, 0x0600);
+        // <IRQ> then INX
+        assert_eq!(cpu.step().unwrap().pc, 0x0800);
+        assert_eq!(cpu.reg_x, 1);
+        // the line is still held, but the handler runs with interrupts masked
+        assert_eq!(cpu.step().unwrap().pc, 0x0801);
+        // RTI cleared the mask again, so the held line interrupts before the NOP
+        assert_eq!(cpu.step().unwrap().pc, 0x0800);
+        assert_eq!(cpu.reg_x, 2);
+        assert_eq!(cpu.stack_ptr, STACK_RST.wrapping_sub(3));
+
+        // released, the program goes on
+        cpu.set_irq(false);
+        assert_eq!(cpu.step().unwrap().pc, 0x0801);
+        assert_eq!(cpu.step().unwrap().pc, 0x0601);
+        assert_eq!(cpu.step().unwrap().stop, Some(StopReason::Break));
+        assert_eq!(cpu.reg_x, 2);
+        assert_eq!(cpu.stack_ptr, STACK_RST);
+    }
+
+    #[test]
+    fn test_brk_software_interrupt() {
+        let mut cpu = cpu_with_vectors(vec![0x00, 0xff, 0xea]);
+        cpu.brk_mode = BrkMode::Interrupt;
+        cpu.run_with_callback(|cpu| {
+            // the vector sends us to the BRK at $0800, stop there
+            cpu.brk_mode = BrkMode::Halt;
+        }).unwrap();
+        // BRK (padding byte $FF) through $FFFE, not the NMI vector
+        assert_eq!(cpu.program_counter, 0x0801);
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);                     // return address high
+        assert_eq!(cpu.mem_read(0x01FC), 0x02);                     // return address skips padding
+        assert_ne!(cpu.mem_read(0x01FB) & Flags::BREAK.bits(), 0);  // B set for BRK
+        assert!(cpu.status.contains(Flags::INTERRUPT));
+        assert_eq!(cpu.cycles(), 14);                               // trapping BRK + halting BRK
+    }
+
+    #[test]
+    fn test_illegal_opcode_error() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        let result = run_from_load_address(&mut cpu, vec![0xa9, 0x05, 0x02]);
+        // LDA #$05
+        // $02 (JAM, not decoded)
+        assert_eq!(result, Err(CpuError::IllegalOpcode { pc: 0x0602, opcode: 0x02 }));
+        assert_eq!(cpu.reg_a, 0x05);
+    }
+
+    #[test]
+    fn test_bus_fault_error() {
+        let bus = Bus::builder()
+            .ram(0x0000, 0x1FFF, 2048)
+            .unsupported(0x4000, 0x4017)
+            .build().unwrap();
+        let mut cpu = CPU::new(bus);
+        let result = run_from_load_address(&mut cpu, vec![0xa9, 0x05, 0x8d, 0x15, 0x40, 0x00]);
+        // LDA #$05
+        // STA $4015 (no APU behind the bus)
+        assert_eq!(result, Err(CpuError::BusFault { pc: 0x0602, addr: 0x4015 }));
+    }
+
+    #[test]
+    fn test_run_frame_with_nmi() {
+        let mut raw = crate::cartridge::test::test_rom(1, 1, 0, 0);
+        let prg = 16;
+        // LDA #$80, STA $2000 (enable NMI), then JMP to itself
+        raw[prg..prg + 8].copy_from_slice(&[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]);
+        // NMI handler: INC $10, RTI
+        raw[prg + 0x100..prg + 0x103].copy_from_slice(&[0xe6, 0x10, 0x40]);
+        // NMI, reset and IRQ vectors
+        raw[prg + 0x3FFA..prg + 0x4000].copy_from_slice(&[0x00, 0x81, 0x00, 0x80, 0x00, 0x81]);
+        let rom = crate::cartridge::Rom::new(&raw).unwrap();
+        let mut cpu = CPU::new(Bus::with_rom(&rom).unwrap());
+        cpu.has_decimal_mode = false;
+        cpu.reset();
+
+        assert_eq!(cpu.run_frame(), Ok(None));
+        // NMI fires with vblank, it is taken at the start of the next frame's first step
+        assert_eq!(cpu.mem_read(0x10), 0);
+        let start = cpu.cycles();
+        assert_eq!(cpu.run_frame(), Ok(None));
+        assert_eq!(cpu.mem_read(0x10), 1);
+        // 341 * 262 dots at 3 dots per cpu cycle, give or take an instruction
+        let frame_cycles = cpu.cycles() - start;
+        assert!((29775..=29787).contains(&frame_cycles), "{} cycles", frame_cycles);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.mem_read(0x10), 2);
+    }
+
+    #[test]
+    fn test_apu_frame_irq() {
+        let mut raw = crate::cartridge::test::test_rom(1, 1, 0, 0);
+        let prg = 16;
+        // LDA #$00, STA $4017 (4 step mode, IRQ on), CLI, then JMP to itself
+        raw[prg..prg + 9].copy_from_slice(&[0xa9, 0x00, 0x8d, 0x17, 0x40, 0x58, 0x4c, 0x06, 0x80]);
+        // IRQ handler: INC $10, LDA $4015 (acknowledge), RTI
+        raw[prg + 0x100..prg + 0x106].copy_from_slice(&[0xe6, 0x10, 0xad, 0x15, 0x40, 0x40]);
+        raw[prg + 0x3FFA..prg + 0x4000].copy_from_slice(&[0x00, 0x81, 0x00, 0x80, 0x00, 0x81]);
+        let rom = crate::cartridge::Rom::new(&raw).unwrap();
+        let mut cpu = CPU::new(Bus::with_rom(&rom).unwrap());
+        cpu.has_decimal_mode = false;
+        cpu.reset();
+        // one IRQ per 29830 cycle APU frame
+        while cpu.cycles() < 3 * 29830 + 100 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.mem_read(0x10), 3);
+    }
+
+    #[test]
+    fn test_read_modify_write_on_mmc1() {
+        let mut raw = crate::cartridge::test::test_rom(8, 1, 0x10, 0);
+        // $FF at $FFF0, in the last bank
+        raw[16 + 7 * 0x4000 + 0x3FF0] = 0xFF;
+        let rom = crate::cartridge::Rom::new(&raw).unwrap();
+        let mut cpu = CPU::new(Bus::with_rom(&rom).unwrap());
+        cpu.brk_mode = BrkMode::Halt;
+        // load would write the reset vector into the serial port, so copy the program to RAM by hand
+        let program = [
+            0xee, 0xf0, 0xff,   // INC $FFF0: writes $FF (reset), then $00 which MMC1 drops
+            0xa9, 0x01,         // LDA #$01
+            0x8d, 0x00, 0xe0,   // STA $E000
+            0xa9, 0x00,         // LDA #$00
+            0x8d, 0x00, 0xe0,   // STA $E000
+            0x8d, 0x00, 0xe0,   // STA $E000
+            0x8d, 0x00, 0xe0,   // STA $E000
+            0x8d, 0x00, 0xe0,   // STA $E000
+            0x00,
+        ];
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.mem_write(0x0600 + offset as u16, byte);
+        }
+        cpu.program_counter = 0x0600;
+        cpu.run().unwrap();
+        // PRG bank 1 at $8000, a shifted in 0 from the INC would have made it bank 2
+        assert_eq!(cpu.mem_read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut cpu = CPU::new(Bus::new());
+        cpu.load(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0xea, 0x8d, 0x14, 0x40]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        // LDA #$02
+        cpu.step().unwrap();
+        // STA $4014, DMA starts on an even cycle
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 513);
+        // NOP
+        cpu.step().unwrap();
+        // STA $4014 again, now on an odd cycle which costs an extra alignment cycle
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 514);
+        assert_eq!(cpu.bus.cycles(), cpu.cycles());
+    }
+
+    #[test]
+    fn test_brk_halt_stop_reason() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        assert_eq!(run_from_load_address(&mut cpu, vec![0xea, 0x00]), Ok(StopReason::Break));
+    }
+
+    #[test]
+    fn test_step_single_instruction() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xa2, 0x01, 0xbd, 0xff, 0x02, 0x00]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+
+        // LDX #$01
+        let step = cpu.step().unwrap();
+        assert_eq!(step.pc, 0x0600);
+        assert_eq!(step.opcode, 0xa2);
+        assert_eq!(step.operand_address, Some(0x0601));
+        assert_eq!(step.cycles, 2);
+        assert_eq!(cpu.reg_x, 0x01);
+        assert_eq!(cpu.program_counter, 0x0602);
+
+        // LDA $02FF,X
+        let step = cpu.step().unwrap();
+        assert_eq!(step.mnemonic, "LDA");
+        assert_eq!(step.operand_address, Some(0x0300));
+        assert_eq!(step.cycles, 5);
+        assert_eq!(step.stop, None);
+
+        // BRK, halting
+        cpu.brk_mode = BrkMode::Halt;
+        let step = cpu.step().unwrap();
+        assert_eq!(step.operand_address, None);
+        assert_eq!(step.stop, Some(StopReason::Break));
+        assert_eq!(cpu.cycles(), 14);
+    }
+
+    #[test]
+    fn test_step_counts_interrupt_entry() {
+        let mut cpu = cpu_with_vectors(vec![0xea]);
+        cpu.brk_mode = BrkMode::Interrupt;
+        cpu.trigger_nmi();
+        // <NMI> then the instruction at the vector, a BRK at $0700 that traps through $FFFE
+        let step = cpu.step().unwrap();
+        assert_eq!(step.pc, 0x0700);
+        assert_eq!(step.cycles, 14);
+        assert_eq!(cpu.program_counter, 0x0800);
+    }
+
+    #[test]
+    fn test_adc_decimal_mode() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00]).unwrap();
+        // SED
+        // CLC
+        // LDA #$09
+        // ADC #$01
+        assert_eq!(cpu.reg_a, 0x10);
+        assert!(!cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xf8, 0x38, 0xa9, 0x58, 0x69, 0x46, 0x00]).unwrap();
+        // SED
+        // SEC
+        // LDA #$58
+        // ADC #$46 (58 + 46 + 1 = 105)
+        assert_eq!(cpu.reg_a, 0x05);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01, 0x00]).unwrap();
+        // SED
+        // CLC
+        // LDA #$99
+        // ADC #$01
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(cpu.status.contains(Flags::CARRY));
+        // NMOS quirk: Z follows the binary sum ($9A), N the unadjusted decimal sum
+        assert!(!cpu.status.contains(Flags::ZERO));
+        assert!(cpu.status.contains(Flags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_sbc_decimal_mode() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xf8, 0x38, 0xa9, 0x10, 0xe9, 0x01, 0x00]).unwrap();
+        // SED
+        // SEC
+        // LDA #$10
+        // SBC #$01
+        assert_eq!(cpu.reg_a, 0x09);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xf8, 0x38, 0xa9, 0x00, 0xe9, 0x01, 0x00]).unwrap();
+        // SED
+        // SEC
+        // LDA #$00
+        // SBC #$01 (borrows)
+        assert_eq!(cpu.reg_a, 0x99);
+        assert!(!cpu.status.contains(Flags::CARRY));
+    }
+
+    #[test]
+    fn test_decimal_disabled_on_2a03() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.has_decimal_mode = false;
+        run_from_load_address(&mut cpu, vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00]).unwrap();
+        // SED
+        // CLC
+        // LDA #$09
+        // ADC #$01
+        assert_eq!(cpu.reg_a, 0x0A);
+        assert!(cpu.status.contains(Flags::DECIMAL));
+    }
+
+    // everything a single instruction can change, compared as a whole so side effects show up too
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Effects {
+        reg_a: u8,
+        reg_x: u8,
+        reg_y: u8,
+        status: u8,
+        stack_ptr: u8,
+        program_counter: u16,
+        // the byte at $0010, where every addressing mode ends up
+        operand: u8,
+        // top of the stack after the instruction
+        stack_top: [u8; 2],
+    }
+
+    fn effects_of(cpu: &mut CPU) -> Effects {
+        let stack_top = STACK + cpu.stack_ptr as u16;
+        Effects {
+            reg_a: cpu.reg_a,
+            reg_x: cpu.reg_x,
+            reg_y: cpu.reg_y,
+            status: cpu.status.bits(),
+            stack_ptr: cpu.stack_ptr,
+            program_counter: cpu.program_counter,
+            operand: cpu.mem_read(0x0010),
+            stack_top: [cpu.mem_read(stack_top.wrapping_add(1)), cpu.mem_read(stack_top.wrapping_add(2))],
+        }
+    }
+
+    // what an official instruction does starting from A=$81, X=Y=0, the flags from reset
+    // and $10 both as the operand byte and at $0010
+    fn expected_effects(opcode: &opcodes::OpCode, before: Effects) -> Effects {
+        let mut after = Effects { program_counter: 0x0600 + opcode.length as u16, ..before };
+        let accumulator = opcode.mode == AddressingMode::NoneAddressing;
+        let set = |after: &mut Effects, flag: Flags, on: bool| {
+            let mut status = Flags::from_bits_truncate(after.status);
+            status.set(flag, on);
+            after.status = status.bits();
+        };
+        let set_nz = |after: &mut Effects, value: u8| {
+            set(after, Flags::ZERO, value == 0);
+            set(after, Flags::NEGATIVE, value & 0x80 != 0);
+        };
+        let branch = |after: &mut Effects, taken: bool| {
+            if taken {
+                after.program_counter = 0x0612;
+            }
+        };
+        match opcode.mnemonic {
+            "ADC" => { after.reg_a = 0x91; set_nz(&mut after, 0x91); }
+            "AND" => { after.reg_a = 0x00; set_nz(&mut after, 0x00); }
+            "ASL" if accumulator => { after.reg_a = 0x02; set_nz(&mut after, 0x02); set(&mut after, Flags::CARRY, true); }
+            "ASL" => { after.operand = 0x20; set_nz(&mut after, 0x20); }
+            "BCC" | "BNE" | "BPL" | "BVC" => branch(&mut after, true),
+            "BCS" | "BEQ" | "BMI" | "BVS" => branch(&mut after, false),
+            "BIT" => set(&mut after, Flags::ZERO, true),
+            // halts before anything is pushed
+            "BRK" => after.program_counter = 0x0601,
+            "CLC" => set(&mut after, Flags::CARRY, false),
+            "CLD" => set(&mut after, Flags::DECIMAL, false),
+            "CLI" => set(&mut after, Flags::INTERRUPT, false),
+            "CLV" => set(&mut after, Flags::OVERFLOW, false),
+            "CMP" => set(&mut after, Flags::CARRY, true),
+            "CPX" | "CPY" => set_nz(&mut after, 0xF0),
+            "DEC" => { after.operand = 0x0F; set_nz(&mut after, 0x0F); }
+            "DEX" => { after.reg_x = 0xFF; set_nz(&mut after, 0xFF); }
+            "DEY" => { after.reg_y = 0xFF; set_nz(&mut after, 0xFF); }
+            "EOR" | "ORA" => { after.reg_a = 0x91; set_nz(&mut after, 0x91); }
+            "INC" => { after.operand = 0x11; set_nz(&mut after, 0x11); }
+            "INX" => { after.reg_x = 0x01; set_nz(&mut after, 0x01); }
+            "INY" => { after.reg_y = 0x01; set_nz(&mut after, 0x01); }
+            "JMP" => after.program_counter = 0x0010,
+            "JSR" => { after.program_counter = 0x0010; after.stack_ptr = 0xFB; after.stack_top = [0x02, 0x06]; }
+            "LDA" => { after.reg_a = 0x10; set_nz(&mut after, 0x10); }
+            "LDX" => { after.reg_x = 0x10; set_nz(&mut after, 0x10); }
+            "LDY" => { after.reg_y = 0x10; set_nz(&mut after, 0x10); }
+            "LSR" if accumulator => { after.reg_a = 0x40; set_nz(&mut after, 0x40); set(&mut after, Flags::CARRY, true); }
+            "LSR" => { after.operand = 0x08; set_nz(&mut after, 0x08); }
+            "NOP" => {}
+            "PHA" => { after.stack_ptr = 0xFC; after.stack_top = [0x81, 0x00]; }
+            "PHP" => { after.stack_ptr = 0xFC; after.stack_top = [0x34, 0x00]; }
+            // the stack above $01FD is still zero
+            "PLA" => { after.reg_a = 0x00; after.stack_ptr = 0xFE; after.stack_top = [0x00, 0x00]; set_nz(&mut after, 0x00); }
+            "PLP" => { after.status = 0x20; after.stack_ptr = 0xFE; after.stack_top = [0x00, 0x00]; }
+            "ROL" if accumulator => { after.reg_a = 0x02; set_nz(&mut after, 0x02); set(&mut after, Flags::CARRY, true); }
+            "ROL" => { after.operand = 0x20; set_nz(&mut after, 0x20); }
+            "ROR" if accumulator => { after.reg_a = 0x40; set_nz(&mut after, 0x40); set(&mut after, Flags::CARRY, true); }
+            "ROR" => { after.operand = 0x08; set_nz(&mut after, 0x08); }
+            "RTI" => { after.status = 0x20; after.program_counter = 0x0000; after.stack_ptr = 0x00; after.stack_top = [0x00, 0x00]; }
+            "RTS" => { after.program_counter = 0x0001; after.stack_ptr = 0xFF; after.stack_top = [0x00, 0x00]; }
+            // $81 - $10 - borrow, positive from negative overflows
+            "SBC" => {
+                after.reg_a = 0x70;
+                set_nz(&mut after, 0x70);
+                set(&mut after, Flags::CARRY, true);
+                set(&mut after, Flags::OVERFLOW, true);
+            }
+            "SEC" => set(&mut after, Flags::CARRY, true),
+            "SED" => set(&mut after, Flags::DECIMAL, true),
+            "SEI" => set(&mut after, Flags::INTERRUPT, true),
+            "STA" => after.operand = 0x81,
+            "STX" | "STY" => after.operand = 0x00,
+            "TAX" => { after.reg_x = 0x81; set_nz(&mut after, 0x81); }
+            "TAY" => { after.reg_y = 0x81; set_nz(&mut after, 0x81); }
+            "TSX" => { after.reg_x = 0xFD; set_nz(&mut after, 0xFD); }
+            "TXA" | "TYA" => { after.reg_a = 0x00; set_nz(&mut after, 0x00); }
+            "TXS" => { after.stack_ptr = 0x00; after.stack_top = [0x00, 0x00]; }
+            mnemonic => panic!("no expected effects for {}", mnemonic),
+        }
+        after
+    }
+
+    #[test]
+    fn test_all_official_opcodes_execute() {
+        assert_eq!(opcodes::OPCODES_MAP.values().filter(|op| !op.unofficial).count(), 151);
+        for opcode in opcodes::OPCODES_MAP.values().filter(|op| !op.unofficial) {
+            let bus = Bus::new();
+            let mut cpu = CPU::new(bus);
+            cpu.brk_mode = BrkMode::Halt;
+            // every addressing mode lands on $0010: the operand bytes, the pointer at $10/$11
+            // and X=Y=0 all point there, and it holds $10 itself
+            cpu.load(vec![opcode.code, 0x10, 0x00]);
+            cpu.reset();
+            cpu.program_counter = 0x0600;
+            cpu.reg_a = 0x81;
+            cpu.mem_write(0x0010, 0x10);
+            cpu.mem_write(0x0011, 0x00);
+
+            let before = effects_of(&mut cpu);
+            let step = cpu.step().unwrap_or_else(|err| panic!("{} ({:#04x}): {}", opcode.mnemonic, opcode.code, err));
+            assert_eq!(step.opcode, opcode.code);
+            assert_eq!(step.mnemonic, opcode.mnemonic);
+            assert_eq!(
+                effects_of(&mut cpu),
+                expected_effects(opcode, before),
+                "{} ({:#04x})",
+                opcode.mnemonic,
+                opcode.code
+            );
+            // nothing crosses a page, so only taken branches cost more than the table says
+            let taken = matches!(opcode.mnemonic, "BCC" | "BNE" | "BPL" | "BVC");
+            assert_eq!(step.cycles, opcode.cycles as u64 + taken as u64, "{} ({:#04x})", opcode.mnemonic, opcode.code);
+        }
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_bug() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x02FF, 0x34);
+        cpu.mem_write(0x0300, 0x12);
+        cpu.mem_write(0x0200, 0x07);
+        cpu.load(vec![0x6c, 0xff, 0x02]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        // JMP ($02FF), high byte comes from $0200 rather than $0300
+        let step = cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0734);
+        assert_eq!(step.operand_address, Some(0x0734));
+    }
+
+    #[test]
+    fn test_jsr_rts_wrap_around_top_of_memory() {
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        // JSR $0300 in the last three bytes, RTS at $0300
+        cpu.mem_write(0xFFFD, 0x20);
+        cpu.mem_write_u16(0xFFFE, 0x0300);
+        cpu.mem_write(0x0300, 0x60);
+        cpu.program_counter = 0xFFFD;
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x0300);
+        assert_eq!(cpu.mem_read(0x01FD), 0xFF);                     // return address high
+        assert_eq!(cpu.mem_read(0x01FC), 0xFF);                     // return address low
+        cpu.step().unwrap();
+        // returns to the byte after $FFFF
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_lax_sax() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x8f);
+        run_from_load_address(&mut cpu, vec![0xa7, 0x10, 0xa9, 0x0f, 0x87, 0x11, 0x00]).unwrap();
+        // LAX $10
+        // LDA #$0F
+        // SAX $11
+        assert_eq!(cpu.reg_x, 0x8f);
+        assert_eq!(cpu.mem_read(0x11), 0x0f);
+    }
+
+    #[test]
+    fn test_read_modify_write_combos() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x06);
+        run_from_load_address(&mut cpu, vec![0xa9, 0x05, 0xc7, 0x10, 0x00]).unwrap();
+        // LDA #$05
+        // DCP $10 ($06 -> $05, compares equal)
+        assert_eq!(cpu.mem_read(0x10), 0x05);
+        assert!(cpu.status.contains(Flags::ZERO));
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x01);
+        run_from_load_address(&mut cpu, vec![0x38, 0xa9, 0x05, 0xe7, 0x10, 0x00]).unwrap();
+        // SEC
+        // LDA #$05
+        // ISB $10 ($01 -> $02, A = 5 - 2)
+        assert_eq!(cpu.mem_read(0x10), 0x02);
+        assert_eq!(cpu.reg_a, 0x03);
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x81);
+        run_from_load_address(&mut cpu, vec![0xa9, 0x01, 0x07, 0x10, 0x00]).unwrap();
+        // LDA #$01
+        // SLO $10 ($81 -> $02 carry out, A = $01 | $02)
+        assert_eq!(cpu.mem_read(0x10), 0x02);
+        assert_eq!(cpu.reg_a, 0x03);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0x03);
+        run_from_load_address(&mut cpu, vec![0x18, 0xa9, 0x10, 0x67, 0x10, 0x00]).unwrap();
+        // CLC
+        // LDA #$10
+        // RRA $10 ($03 -> $01 carry out, A = $10 + $01 + 1)
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.reg_a, 0x12);
+    }
+
+    #[test]
+    fn test_immediate_combos() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa9, 0xff, 0x0b, 0x80, 0x00]).unwrap();
+        // LDA #$FF
+        // ANC #$80
+        assert_eq!(cpu.reg_a, 0x80);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa9, 0xff, 0x4b, 0x03, 0x00]).unwrap();
+        // LDA #$FF
+        // ALR #$03
+        assert_eq!(cpu.reg_a, 0x01);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0x38, 0xa9, 0xff, 0x6b, 0xc0, 0x00]).unwrap();
+        // SEC
+        // LDA #$FF
+        // ARR #$C0 ($C0 ror with carry = $E0)
+        assert_eq!(cpu.reg_a, 0xe0);
+        assert!(cpu.status.contains(Flags::CARRY));
+        assert!(!cpu.status.contains(Flags::OVERFLOW));
+
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        run_from_load_address(&mut cpu, vec![0xa9, 0x0f, 0xa2, 0x3c, 0xcb, 0x02, 0x00]).unwrap();
+        // LDA #$0F
+        // LDX #$3C
+        // SBX #$02 (($0F & $3C) - 2)
+        assert_eq!(cpu.reg_x, 0x0a);
+        assert!(cpu.status.contains(Flags::CARRY));
+    }
+
+    #[test]
+    fn test_strict_variant_rejects_unofficial() {
+        for opcode in opcodes::OPCODES_MAP.values().filter(|op| op.unofficial) {
+            let bus = Bus::new();
+            let mut cpu = CPU::new(bus);
+            cpu.has_unofficial_opcodes = false;
+            let result = run_from_load_address(&mut cpu, vec![opcode.code, 0x10, 0x00]);
+            assert_eq!(result, Err(CpuError::IllegalOpcode { pc: 0x0600, opcode: opcode.code }));
+        }
+    }
+
+    #[test]
+    fn test_strict_2a03() {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        cpu.has_decimal_mode = false;
+        cpu.has_unofficial_opcodes = false;
+        // SED, CLC, LDA #$09, ADC #$01 stays binary
+        run_from_load_address(&mut cpu, vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00]).unwrap();
+        assert_eq!(cpu.reg_a, 0x0A);
+        // SBX #$02 traps
+        let result = run_from_load_address(&mut cpu, vec![0xcb, 0x02, 0x00]);
+        assert_eq!(result, Err(CpuError::IllegalOpcode { pc: 0x0600, opcode: 0xcb }));
+    }
+
+    // run a single instruction at $0600 and report the effective address it used
+    fn operand_address_of(program: Vec<u8>, reg_x: u8, reg_y: u8, zero_page: &[(u16, u8)]) -> Step {
+        let bus = Bus::new();
+        let mut cpu = CPU::new(bus);
+        for &(addr, data) in zero_page {
+            cpu.mem_write(addr, data);
+        }
+        cpu.load(program);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        cpu.reg_x = reg_x;
+        cpu.reg_y = reg_y;
+        cpu.step().unwrap()
+    }
+
+    #[test]
+    fn test_zero_page_indexed_wraps() {
+        // LDA $F0,X
+        let step = operand_address_of(vec![0xb5, 0xf0], 0x20, 0, &[]);
+        assert_eq!(step.operand_address, Some(0x0010));
+        // LDX $F0,Y
+        let step = operand_address_of(vec![0xb6, 0xf0], 0, 0x20, &[]);
+        assert_eq!(step.operand_address, Some(0x0010));
+    }
+
+    #[test]
+    fn test_absolute_indexed() {
+        // LDA $0280,X
+        let step = operand_address_of(vec![0xbd, 0x80, 0x02], 0x10, 0, &[]);
+        assert_eq!(step.operand_address, Some(0x0290));
+        assert_eq!(step.cycles, 4);
+        // LDA $0280,Y crossing into page 3
+        let step = operand_address_of(vec![0xb9, 0x80, 0x02], 0, 0x90, &[]);
+        assert_eq!(step.operand_address, Some(0x0310));
+        assert_eq!(step.cycles, 5);
+        // STA $0280,Y pays no extra cycle for the crossing
+        let step = operand_address_of(vec![0x99, 0x80, 0x02], 0, 0x90, &[]);
+        assert_eq!(step.operand_address, Some(0x0310));
+        assert_eq!(step.cycles, 5);
+    }
+
+    #[test]
+    fn test_indexed_indirect() {
+        // LDA ($20,X)
+        let step = operand_address_of(vec![0xa1, 0x20], 0x04, 0, &[(0x24, 0x74), (0x25, 0x02)]);
+        assert_eq!(step.operand_address, Some(0x0274));
+        // LDA ($F0,X), pointer wraps around the zero page
+        let step = operand_address_of(vec![0xa1, 0xf0], 0x14, 0, &[(0x04, 0x74), (0x05, 0x02)]);
+        assert_eq!(step.operand_address, Some(0x0274));
+        // LDA ($FF,X) with X = 0, high byte read from $00
+        let step = operand_address_of(vec![0xa1, 0xff], 0, 0, &[(0xff, 0x74), (0x00, 0x02)]);
+        assert_eq!(step.operand_address, Some(0x0274));
+    }
+
+    #[test]
+    fn test_indirect_indexed() {
+        // LDA ($20),Y adds Y after dereferencing the pointer
+        let step = operand_address_of(vec![0xb1, 0x20], 0, 0x10, &[(0x20, 0x00), (0x21, 0x03), (0x30, 0x55)]);
+        assert_eq!(step.operand_address, Some(0x0310));
+        assert_eq!(step.cycles, 5);
+        // LDA ($20),Y crossing a page costs a cycle
+        let step = operand_address_of(vec![0xb1, 0x20], 0, 0x10, &[(0x20, 0xf8), (0x21, 0x02)]);
+        assert_eq!(step.operand_address, Some(0x0308));
+        assert_eq!(step.cycles, 6);
+        // LDA ($FF),Y, high byte of the pointer comes from $00
+        let step = operand_address_of(vec![0xb1, 0xff], 0, 0x01, &[(0xff, 0x00), (0x00, 0x03)]);
+        assert_eq!(step.operand_address, Some(0x0301));
+        // STA ($20),Y is always 6 cycles
+        let step = operand_address_of(vec![0x91, 0x20], 0, 0x10, &[(0x20, 0xf8), (0x21, 0x02)]);
+        assert_eq!(step.operand_address, Some(0x0308));
+        assert_eq!(step.cycles, 6);
+    }
+
+    // minimal memory with all 64 KiB backed, to check the core runs on other Mem implementations
+    struct TestRam {
+        memory: Vec<u8>,
+    }
+
+    impl Mem for TestRam {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.memory[addr as usize]
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.memory[addr as usize] = data;
+        }
+    }
+
+    #[test]
+    fn test_cpu_with_custom_mem() {
+        let ram = TestRam { memory: vec![0; 0x10000] };
+        let mut cpu = CPU::new(ram);
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(vec![0xa9, 0x42, 0x8d, 0x00, 0xc0, 0x00]);
+        cpu.reset();
+        // LDA #$42
+        // STA $C000
+        // BRK
+        assert_eq!(cpu.program_counter, 0x0600);   // reset vector written by load is readable
+        assert_eq!(cpu.run(), Ok(StopReason::Break));
+        assert_eq!(cpu.bus.memory[0xc000], 0x42);
+    }
+
+    #[test]
+    fn test_cpu_with_flat_ram() {
+        let ram = FlatRam::new();
+        let mut cpu = CPU::new(ram);
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(vec![0xa9, 0x42, 0x8d, 0x00, 0xc0, 0x00]);
+        cpu.reset();
+        // LDA #$42
+        // STA $C000
+        // BRK
+        assert_eq!(cpu.program_counter, 0x0600);   // reset vector written by load is readable
+        assert_eq!(cpu.run(), Ok(StopReason::Break));
+        assert_eq!(cpu.mem_read(0xc000), 0x42);
+    }
 }
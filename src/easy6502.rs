@@ -0,0 +1,298 @@
+// the easy6502 virtual machine: 64 KiB of RAM with a 32x32 screen at $0200-$05FF, a random byte at $FE
+// and the last key pressed at $FF. Programs load at $0600 like everywhere else
+use crate::cpu::Mem;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+const MEMORY_SIZE: usize = 0x10000;
+pub const SCREEN: u16 = 0x0200;
+pub const SCREEN_END: u16 = 0x05FF;
+pub const SCREEN_SIZE: usize = 32;
+pub const RANDOM: u16 = 0x00FE;
+pub const LAST_KEY: u16 = 0x00FF;
+
+// easy6502 runs far slower than a real 6502, its programs (snake in particular) count on it.
+// This many cpu cycles per 60 Hz frame is roughly the speed of the original
+pub const CYCLES_PER_FRAME: u64 = 700;
+
+// colour of each screen byte, only the low nibble counts
+pub static PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00), // black
+    (0xFF, 0xFF, 0xFF), // white
+    (0x88, 0x00, 0x00), // red
+    (0xAA, 0xFF, 0xEE), // cyan
+    (0xCC, 0x44, 0xCC), // purple
+    (0x00, 0xCC, 0x55), // green
+    (0x00, 0x00, 0xAA), // blue
+    (0xEE, 0xEE, 0x77), // yellow
+    (0xDD, 0x88, 0x55), // orange
+    (0x66, 0x44, 0x00), // brown
+    (0xFF, 0x77, 0x77), // light red
+    (0x33, 0x33, 0x33), // dark grey
+    (0x77, 0x77, 0x77), // grey
+    (0xAA, 0xFF, 0x66), // light green
+    (0x00, 0x88, 0xFF), // light blue
+    (0xBB, 0xBB, 0xBB), // light grey
+];
+
+pub struct Easy6502 {
+    memory: Box<[u8]>,
+    rng: StdRng,
+    cycles: u64,
+    frame_complete: bool,
+    // set when the screen was written since the last call to take_screen_changed
+    screen_changed: bool,
+    // wall clock pacing, off unless asked for so tests run at full speed
+    frame_duration: Option<Duration>,
+    next_frame: Instant,
+}
+
+impl Default for Easy6502 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Easy6502 {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    // same sequence of random numbers on every run
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Easy6502 {
+            memory: vec![0; MEMORY_SIZE].into_boxed_slice(),
+            rng,
+            cycles: 0,
+            frame_complete: false,
+            screen_changed: false,
+            frame_duration: None,
+            next_frame: Instant::now(),
+        }
+    }
+
+    // sleep between frames so the program runs at frames_per_second
+    pub fn throttle(mut self, frames_per_second: u32) -> Self {
+        self.frame_duration = Some(Duration::from_secs(1) / frames_per_second);
+        self.next_frame = Instant::now();
+        self
+    }
+
+    // ASCII code of the key the program should see as pressed last
+    pub fn set_key(&mut self, key: u8) {
+        self.memory[LAST_KEY as usize] = key;
+    }
+
+    // palette index of a screen pixel
+    pub fn screen_color(&self, x: usize, y: usize) -> u8 {
+        self.memory[SCREEN as usize + y * SCREEN_SIZE + x] & 0x0F
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        PALETTE[self.screen_color(x, y) as usize]
+    }
+
+    // the screen as rows of RGB pixels
+    pub fn screen_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(SCREEN_SIZE * SCREEN_SIZE * 3);
+        for y in 0..SCREEN_SIZE {
+            for x in 0..SCREEN_SIZE {
+                let (r, g, b) = self.pixel(x, y);
+                rgb.extend_from_slice(&[r, g, b]);
+            }
+        }
+        rgb
+    }
+
+    pub fn take_screen_changed(&mut self) -> bool {
+        std::mem::take(&mut self.screen_changed)
+    }
+
+    // whether a frame worth of cycles ran since the previous call
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_complete = true;
+        if let Some(frame_duration) = self.frame_duration {
+            self.next_frame += frame_duration;
+            let now = Instant::now();
+            if self.next_frame > now {
+                spin_sleep::sleep(self.next_frame - now);
+            } else {
+                // running behind, don't try to catch up
+                self.next_frame = now;
+            }
+        }
+    }
+}
+
+impl Mem for Easy6502 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            // a fresh random byte on every read
+            RANDOM => self.rng.gen(),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        if (SCREEN..=SCREEN_END).contains(&addr) && self.memory[addr as usize] != data {
+            self.screen_changed = true;
+        }
+        self.memory[addr as usize] = data;
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        let frame_before = self.cycles / CYCLES_PER_FRAME;
+        self.cycles += cycles;
+        if self.cycles / CYCLES_PER_FRAME != frame_before {
+            self.end_frame();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{BrkMode, CPU};
+
+    #[test]
+    fn test_screen_colors() {
+        let mut cpu = CPU::new(Easy6502::new());
+        // test.asm
+        cpu.load_run(vec![0xa9, 0x01, 0x8d, 0x00, 0x02, 0xa9, 0x05, 0x8d, 0x01, 0x02, 0xa9, 0x08, 0x8d, 0x02, 0x02])
+            .unwrap();
+        assert!(cpu.bus.take_screen_changed());
+        assert_eq!(cpu.bus.pixel(0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(cpu.bus.pixel(1, 0), (0x00, 0xCC, 0x55));
+        assert_eq!(cpu.bus.pixel(2, 0), (0xDD, 0x88, 0x55));
+        assert_eq!(cpu.bus.pixel(0, 1), (0x00, 0x00, 0x00));
+        let rgb = cpu.bus.screen_rgb();
+        assert_eq!(rgb.len(), 32 * 32 * 3);
+        assert_eq!(&rgb[3..6], &[0x00, 0xCC, 0x55]);
+        // bottom right corner is $05FF, high nibble ignored
+        cpu.bus.mem_write(SCREEN_END, 0xF2);
+        assert_eq!(cpu.bus.pixel(31, 31), (0x88, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_random_and_key() {
+        let mut first = Easy6502::with_seed(6502);
+        let mut second = Easy6502::with_seed(6502);
+        let sequence: Vec<u8> = (0..16).map(|_| first.mem_read(RANDOM)).collect();
+        assert_eq!(sequence, (0..16).map(|_| second.mem_read(RANDOM)).collect::<Vec<u8>>());
+        assert!(sequence.iter().any(|&byte| byte != sequence[0]));
+
+        first.set_key(b'w');
+        assert_eq!(first.mem_read(LAST_KEY), 0x77);
+    }
+
+    // snake from the easy6502 tutorial, assembled. w/a/s/d steer, the apple at ($00) is a random
+    // colour from $FE and the snake's segments are kept from $10 on, $03 holding their length in bytes
+    const SNAKE: [u8; 309] = [
+        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
+        0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
+        0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
+        0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
+        0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
+        0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
+        0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
+        0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
+        0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
+        0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
+        0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
+        0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
+        0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
+        0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
+        0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
+        0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
+        0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
+        0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
+        0xea, 0xca, 0xd0, 0xfb, 0x60,
+    ];
+
+    // head of the snake as (x, y)
+    fn snake_head(cpu: &mut CPU<Easy6502>) -> (usize, usize) {
+        let offset = cpu.mem_read_u16(0x10) - SCREEN;
+        (offset as usize % SCREEN_SIZE, offset as usize / SCREEN_SIZE)
+    }
+
+    fn white_pixels(machine: &Easy6502) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for y in 0..SCREEN_SIZE {
+            for x in 0..SCREEN_SIZE {
+                if machine.screen_color(x, y) == 1 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    // hold a key until the game gets where we want, the game never stops on its own
+    fn steer(cpu: &mut CPU<Easy6502>, key: u8, mut done: impl FnMut(&mut CPU<Easy6502>) -> bool) {
+        cpu.bus.set_key(key);
+        for _ in 0..100_000 {
+            assert_eq!(cpu.step().unwrap().stop, None);
+            if done(cpu) {
+                return;
+            }
+        }
+        panic!("snake never got there with key {}", key as char);
+    }
+
+    #[test]
+    fn test_snake() {
+        let mut cpu = CPU::new(Easy6502::with_seed(6502));
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(SNAKE.to_vec());
+        cpu.reset();
+
+        // a fixed number of steps in, heading right from the middle
+        cpu.bus.set_key(b'd');
+        for _ in 0..3000 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(snake_head(&mut cpu), (20, 16));
+        assert_eq!(white_pixels(&cpu.bus), vec![(19, 16), (20, 16)]);
+        // the seed decides where the apple goes
+        let apple = cpu.mem_read_u16(0x00);
+        assert_eq!(apple, 0x055c);
+        assert_ne!(cpu.bus.screen_color(28, 26), 0);
+
+        // down to the apple's row, then right into it. The head moves a little before it's drawn
+        steer(&mut cpu, b's', |cpu| cpu.bus.screen_color(20, 26) == 1);
+        assert_eq!(white_pixels(&cpu.bus), vec![(20, 25), (20, 26)]);
+        // eating it adds a segment
+        steer(&mut cpu, b'd', |cpu| cpu.mem_read(0x03) == 6);
+        assert_eq!(snake_head(&mut cpu), (28, 26));
+
+        // the longer snake keeps all three segments on screen and there's a new apple
+        steer(&mut cpu, b'd', |cpu| cpu.bus.screen_color(30, 26) == 1);
+        assert_eq!(white_pixels(&cpu.bus), vec![(28, 26), (29, 26), (30, 26)]);
+        assert_ne!(cpu.mem_read_u16(0x00), apple);
+    }
+
+    #[test]
+    fn test_frames_by_cycles() {
+        let mut cpu = CPU::new(Easy6502::new());
+        cpu.brk_mode = BrkMode::Halt;
+        // JMP to itself, 3 cycles each
+        cpu.load(vec![0x4c, 0x00, 0x06]);
+        cpu.reset();
+        for _ in 0..(CYCLES_PER_FRAME / 3) {
+            cpu.step().unwrap();
+            assert!(!cpu.bus.take_frame_complete());
+        }
+        cpu.step().unwrap();
+        assert!(cpu.bus.take_frame_complete());
+    }
+}
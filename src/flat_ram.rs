@@ -0,0 +1,33 @@
+use crate::cpu::Mem;
+
+const MEMORY_SIZE: usize = 0x10000;
+
+// a full 64 KiB address space of plain RAM, nothing mirrored and nothing mapped over it.
+// matches easy6502 style and bare 6502 environments, so the reset and interrupt vectors work
+pub struct FlatRam {
+    memory: Box<[u8]>,
+}
+
+impl Default for FlatRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatRam {
+    pub fn new() -> Self {
+        FlatRam {
+            memory: vec![0; MEMORY_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Mem for FlatRam {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+}
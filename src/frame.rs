@@ -0,0 +1,35 @@
+// 256x240 RGB picture the PPU renders into, 3 bytes per pixel, row by row
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+pub struct Frame {
+    pub data: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * WIDTH + x) * 3;
+        if base + 2 < self.data.len() {
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * WIDTH + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+}
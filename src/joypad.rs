@@ -0,0 +1,253 @@
+// standard controllers at $4016/$4017, and scripted input for playing them back frame by frame
+use crate::bus::Device;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+bitflags! {
+    // in the order the shift register reports them, A first
+    pub struct JoypadButton: u8 {
+        const A =           0b00000001;
+        const B =           0b00000010;
+        const SELECT =      0b00000100;
+        const START =       0b00001000;
+        const UP =          0b00010000;
+        const DOWN =        0b00100000;
+        const LEFT =        0b01000000;
+        const RIGHT =       0b10000000;
+    }
+}
+
+pub struct Joypad {
+    // while strobe is high the register keeps reloading, so reads always return A
+    strobe: bool,
+    button_index: u8,
+    pub buttons: JoypadButton,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            buttons: JoypadButton::empty(),
+        }
+    }
+
+    pub fn set_button_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.buttons.set(button, pressed);
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    // one button per read, official controllers report 1 once all eight are out
+    pub fn read(&mut self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        let pressed = (self.buttons.bits() >> self.button_index) & 1;
+        if !self.strobe {
+            self.button_index += 1;
+        }
+        pressed
+    }
+}
+
+// both controller ports. A write to $4016 strobes both, reads from $4016/$4017 shift out one each
+#[derive(Default)]
+pub struct JoypadPorts {
+    pub joypads: [Joypad; 2],
+}
+
+impl Device for JoypadPorts {
+    fn read(&mut self, offset: u16) -> u8 {
+        // the upper bits are open bus, usually the high byte of the address
+        0x40 | self.joypads[(offset & 1) as usize].read()
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        if offset == 0 {
+            for joypad in self.joypads.iter_mut() {
+                joypad.write(data);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(io::Error),
+    // line numbers start at 1
+    BadToken { line: usize, token: String },
+    // more frames on one line than MAX_REPEAT
+    RepeatTooLong { line: usize, count: usize },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io(err) => write!(f, "could not read input script: {}", err),
+            ScriptError::BadToken { line, token } => write!(f, "line {}: unknown button '{}'", line, token),
+            ScriptError::RepeatTooLong { line, count } => {
+                write!(f, "line {}: {} frames is more than the {} a line can hold", line, count, MAX_REPEAT)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<io::Error> for ScriptError {
+    fn from(err: io::Error) -> Self {
+        ScriptError::Io(err)
+    }
+}
+
+// an hour at 60 frames a second, so a typo can't eat all the memory
+const MAX_REPEAT: usize = 60 * 60 * 60;
+
+// buttons to hold on each frame, for both players. One line per frame:
+//
+//     # comment, blank lines are frames with nothing pressed
+//     START
+//     30 RIGHT A       <- a leading number holds the buttons for that many frames
+//     LEFT | UP        <- buttons after | are for player 2
+//
+// past the end of the script nothing is pressed
+pub struct InputScript {
+    frames: Vec<[JoypadButton; 2]>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, ScriptError> {
+        let mut frames = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut repeat = 1;
+            let mut player = 0;
+            let mut buttons = [JoypadButton::empty(); 2];
+            for (position, token) in line.split_whitespace().enumerate() {
+                if token == "|" {
+                    player = 1;
+                    continue;
+                }
+                if position == 0 {
+                    if let Ok(count) = token.parse() {
+                        if count > MAX_REPEAT {
+                            return Err(ScriptError::RepeatTooLong { line: number + 1, count });
+                        }
+                        repeat = count;
+                        continue;
+                    }
+                }
+                let button = match token.to_ascii_uppercase().as_str() {
+                    "A" => JoypadButton::A,
+                    "B" => JoypadButton::B,
+                    "SELECT" => JoypadButton::SELECT,
+                    "START" => JoypadButton::START,
+                    "UP" => JoypadButton::UP,
+                    "DOWN" => JoypadButton::DOWN,
+                    "LEFT" => JoypadButton::LEFT,
+                    "RIGHT" => JoypadButton::RIGHT,
+                    "-" => JoypadButton::empty(),
+                    _ => return Err(ScriptError::BadToken { line: number + 1, token: token.to_string() }),
+                };
+                buttons[player].insert(button);
+            }
+            frames.resize(frames.len() + repeat, buttons);
+        }
+        Ok(InputScript { frames })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<InputScript, ScriptError> {
+        let text = fs::read_to_string(path)?;
+        InputScript::parse(&text)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // buttons for players 1 and 2 on the given frame
+    pub fn buttons(&self, frame: usize) -> [JoypadButton; 2] {
+        self.frames.get(frame).copied().unwrap_or([JoypadButton::empty(); 2])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_and_shift() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed(JoypadButton::A, true);
+        joypad.set_button_pressed(JoypadButton::START, true);
+        joypad.set_button_pressed(JoypadButton::RIGHT, true);
+        joypad.write(1);
+        // strobe held, A over and over
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+        joypad.write(0);
+        let reads: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(reads, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        // strobing again restarts from A
+        joypad.set_button_pressed(JoypadButton::A, false);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut ports = JoypadPorts::default();
+        ports.joypads[1].set_button_pressed(JoypadButton::B, true);
+        ports.write(0, 1);
+        ports.write(0, 0);
+        assert_eq!(ports.read(0), 0x40);
+        assert_eq!(ports.read(1), 0x40);
+        assert_eq!(ports.read(1), 0x41);
+        assert_eq!(ports.read(0), 0x40);
+    }
+
+    #[test]
+    fn test_input_script() {
+        let script = InputScript::parse("# title screen\n\nSTART\n3 right a\nLEFT | UP B\n").unwrap();
+        assert_eq!(script.len(), 6);
+        assert_eq!(script.buttons(0), [JoypadButton::empty(); 2]);
+        assert_eq!(script.buttons(1)[0], JoypadButton::START);
+        assert_eq!(script.buttons(2)[0], JoypadButton::RIGHT | JoypadButton::A);
+        assert_eq!(script.buttons(4)[0], JoypadButton::RIGHT | JoypadButton::A);
+        assert_eq!(script.buttons(5), [JoypadButton::LEFT, JoypadButton::UP | JoypadButton::B]);
+        assert_eq!(script.buttons(6), [JoypadButton::empty(); 2]);
+
+        assert!(matches!(
+            InputScript::parse("START\nJUMP\n"),
+            Err(ScriptError::BadToken { line: 2, ref token }) if token == "JUMP"
+        ));
+        assert_eq!(InputScript::parse("216000 A\n").unwrap().len(), MAX_REPEAT);
+        assert!(matches!(
+            InputScript::parse("START\n99999999999 A\n"),
+            Err(ScriptError::RepeatTooLong { line: 2, count: 99999999999 })
+        ));
+    }
+}
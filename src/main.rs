@@ -1,13 +1,34 @@
 pub mod cpu;
 pub mod opcodes;
 pub mod bus;
+pub mod flat_ram;
+pub mod cartridge;
+pub mod mapper;
+pub mod ppu;
+pub mod frame;
+pub mod palette;
+pub mod render;
+pub mod joypad;
+pub mod apu;
+pub mod wav;
+pub mod easy6502;
+pub mod terminal;
+pub mod snapshot;
 
 use cpu::CPU;
+use cpu::Mem;
 use bus::Bus;
+use flat_ram::FlatRam;
+use easy6502::Easy6502;
+use terminal::Terminal;
+use cartridge::Rom;
+use joypad::InputScript;
 use cpu::Flags;
+use cpu::BrkMode;
 use std::fs;
 use asm6502::assemble;
 use std::env;
+use std::path::Path;
 
 
 #[macro_use]
@@ -15,7 +36,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate bitflags;
 
-fn pretty_print_flags(cpu: &mut CPU) {
+fn pretty_print_flags<M: Mem>(cpu: &mut CPU<M>) {
     let mut pretty_flags: Vec<String> = vec![];
     if cpu.status.contains(Flags::NEGATIVE) {
         pretty_flags.push(String::from("1"));
@@ -66,15 +87,15 @@ fn pretty_print_flags(cpu: &mut CPU) {
     }
 
     let (first, rest) = pretty_flags.split_first_mut().unwrap();
-    first.push_str(" ");
+    first.push(' ');
     for item in rest.iter() {
         first.push_str(item.as_str());
-        first.push_str(" ");
+        first.push(' ');
     }
     println!("{}", &first);
 }
 
-fn print_regs(cpu: &mut CPU) {
+fn print_regs<M: Mem>(cpu: &mut CPU<M>) {
     println!("********************");
     println!("Flags Status:       ");
     println!("N O B B D I Z C     ");
@@ -85,10 +106,62 @@ fn print_regs(cpu: &mut CPU) {
     println!("********************");
 }
 
+// flags followed by a value, which must not be taken for the file name
+const VALUE_FLAGS: [&str; 5] = ["--frames", "--input", "--wav", "--snapshot", "--steps"];
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    args.get(index + 1).map(|value| value.as_str())
+}
+
+// a flag's value as a count, exiting with a message when it isn't one
+fn count_flag(args: &[String], flag: &str) -> Option<usize> {
+    flag_value(args, flag).map(|value| match value.parse() {
+        Ok(count) => count,
+        Err(_) => {
+            eprintln!("{} takes a number, not '{}'", flag, value);
+            std::process::exit(1);
+        }
+    })
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     println!("{:?}", args);
-    let filename = &args[1];
+    // --flat runs the program on a plain 64 KiB RAM instead of the NES bus
+    let flat = args.iter().any(|arg| arg == "--flat");
+    // --easy6502 runs it in the easy6502 machine at 60 frames a second instead of stepping through it
+    // --terminal does the same but draws the screen live in the terminal and passes keys to it
+    let live = args.iter().any(|arg| arg == "--terminal");
+    // --snapshot saves the screen when the program is done, as PNG for a .png file and PPM otherwise.
+    // For .asm files that's the easy6502 screen, --steps stops the program after that many instructions
+    let snapshot = flag_value(&args, "--snapshot");
+    let steps = count_flag(&args, "--steps");
+    let easy = live || snapshot.is_some() || args.iter().any(|arg| arg == "--easy6502");
+    let filename = args.iter().enumerate().skip(1)
+        .find(|(i, arg)| !arg.starts_with("--") && !VALUE_FLAGS.contains(&args[i - 1].as_str()))
+        .map(|(_, arg)| arg)
+        .expect("Usage: hw2_cpu [--flat | --easy6502 | --terminal] <file.asm | file.nes> [--frames N] [--input buttons.txt] [--wav out.wav] [--snapshot out.ppm] [--steps N]");
+
+    if filename.ends_with(".nes") {
+        // --frames and --input run the cartridge headless instead of stepping through it
+        let frames = count_flag(&args, "--frames");
+        let input = flag_value(&args, "--input").map(|path| match InputScript::from_file(path) {
+            Ok(script) => script,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        });
+        let wav = flag_value(&args, "--wav");
+        // only a headless run records audio
+        if wav.is_some() && frames.is_none() && input.is_none() {
+            eprintln!("--wav needs --frames or --input");
+            std::process::exit(1);
+        }
+        run_cartridge(filename, frames, input, wav, snapshot, steps);
+        return;
+    }
 
     //let filename = "src/test.asm";
     println!("In file {}", filename);
@@ -106,21 +179,248 @@ fn main() {
     println!("{:?}", buf);
 
     // load the game
-    let bus = Bus::new();
+    // programs end on BRK, so stop there instead of trapping
+    if easy {
+        // snapshots are compared between runs, so no pacing and the same random numbers every time
+        let machine = if snapshot.is_some() { Easy6502::with_seed(0) } else { Easy6502::new().throttle(60) };
+        let mut cpu = CPU::new(machine);
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(buf);
+        cpu.reset();
+        if live {
+            run_easy6502_live(&mut cpu);
+        } else {
+            run_easy6502(&mut cpu, steps);
+        }
+        if let Some(path) = snapshot {
+            save_snapshot(path, easy6502::SCREEN_SIZE, easy6502::SCREEN_SIZE, &cpu.bus.screen_rgb());
+        }
+    } else if flat {
+        let mut cpu = CPU::new(FlatRam::new());
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(buf);
+        cpu.reset();
+        if let Err(err) = debug_session(&mut cpu) {
+            eprintln!("CPU stopped: {}", err);
+            std::process::exit(1);
+        }
+    } else {
+        let mut cpu = CPU::new(Bus::new());
+        cpu.brk_mode = BrkMode::Halt;
+        cpu.load(buf);
+        cpu.reset();
+        // the NES bus has nothing behind the reset vector, start at the load address
+        cpu.program_counter = 0x0600;
+        if let Err(err) = debug_session(&mut cpu) {
+            eprintln!("CPU stopped: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// load an iNES cartridge and start it from its reset vector
+fn run_cartridge(
+    filename: &str,
+    frames: Option<usize>,
+    input: Option<InputScript>,
+    wav: Option<&str>,
+    snapshot: Option<&str>,
+    steps: Option<usize>,
+) {
+    let rom = match Rom::from_file(filename) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("{}: {}", filename, err);
+            std::process::exit(1);
+        }
+    };
+    println!("Mapper {}, {} KiB PRG-ROM, {} KiB CHR-ROM", rom.mapper, rom.prg_rom.len() / 1024, rom.chr_rom.len() / 1024);
+
+    let bus = match Bus::with_rom(&rom) {
+        Ok(bus) => bus,
+        Err(err) => {
+            eprintln!("{}: {}", filename, err);
+            std::process::exit(1);
+        }
+    };
     let mut cpu = CPU::new(bus);
-    cpu.load(buf);
+    // the 2A03 has no decimal mode
+    cpu.has_decimal_mode = false;
+
+    // battery backed RAM lives in a .sav file next to the ROM
+    let save_path = Path::new(filename).with_extension("sav");
+    if rom.battery {
+        match cpu.bus.load_prg_ram(&save_path) {
+            Ok(None) => {}
+            Ok(Some((file_size, ram_size))) => eprintln!(
+                "{}: warning: save is {} bytes but the cartridge has {} bytes of PRG-RAM",
+                save_path.display(),
+                file_size,
+                ram_size
+            ),
+            Err(err) => eprintln!("{}: {}", save_path.display(), err),
+        }
+    }
     cpu.reset();
-    cpu.program_counter = 0x0600;
 
+    let frames = frames.or_else(|| input.as_ref().map(|script| script.len()));
+    // a snapshot or a step count means nobody is there to step through it either
+    let result = match frames {
+        Some(frames) => run_headless(&mut cpu, frames, input, wav),
+        None if snapshot.is_some() || steps.is_some() => run_steps(&mut cpu, steps),
+        None => debug_session(&mut cpu),
+    };
+    if let (Some(path), Some(ppu)) = (snapshot, cpu.bus.ppu()) {
+        save_snapshot(path, frame::WIDTH, frame::HEIGHT, &ppu.borrow().frame.data);
+    }
+
+    // saved even after a crash, the game may have written it long before
+    if rom.battery {
+        if let Err(err) = cpu.bus.save_prg_ram(&save_path) {
+            eprintln!("{}: {}", save_path.display(), err);
+        }
+    }
+    if let Err(err) = result {
+        eprintln!("CPU stopped: {}", err);
+        std::process::exit(1);
+    }
+}
+
+// run a number of frames without stopping, feeding the controllers from the input script
+// and optionally recording the audio
+fn run_headless(cpu: &mut CPU, frames: usize, input: Option<InputScript>, wav: Option<&str>) -> Result<(), cpu::CpuError> {
+    let apu = cpu.bus.apu();
+    let mut samples = vec![];
+    for frame in 0..frames {
+        if let Some(script) = &input {
+            let [player_1, player_2] = script.buttons(frame);
+            cpu.bus.set_buttons(0, player_1);
+            cpu.bus.set_buttons(1, player_2);
+        }
+        match cpu.run_frame() {
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+                println!("CPU stopped on frame {}: {:?}", frame, reason);
+                break;
+            }
+            Err(err) => {
+                eprintln!("CPU stopped on frame {}", frame);
+                return Err(err);
+            }
+        }
+        if let Some(apu) = &apu {
+            samples.extend(apu.borrow_mut().take_samples());
+        }
+    }
+    println!("Ran {} frames, {} cpu cycles", frames, cpu.cycles());
+
+    if let (Some(path), Some(apu)) = (wav, &apu) {
+        let sample_rate = apu.borrow().sample_rate();
+        if let Err(err) = wav::save_wav(path, sample_rate, &wav::remove_dc(sample_rate, &samples)) {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        }
+        println!("Wrote {} samples to {}", samples.len(), path);
+    }
+    Ok(())
+}
+
+// run an easy6502 program until it stops, then show what it left on the screen
+fn run_easy6502(cpu: &mut CPU<Easy6502>, steps: Option<usize>) {
+    if let Err(err) = run_steps(cpu, steps) {
+        eprintln!("CPU stopped: {}", err);
+        std::process::exit(1);
+    }
+    print_screen(&cpu.bus);
+}
+
+// run until the cpu stops, or for at most that many instructions
+fn run_steps<M: Mem>(cpu: &mut CPU<M>, steps: Option<usize>) -> Result<(), cpu::CpuError> {
+    match steps {
+        Some(steps) => {
+            for _ in 0..steps {
+                if cpu.step()?.stop.is_some() {
+                    break;
+                }
+            }
+        }
+        None => {
+            cpu.run()?;
+        }
+    }
+    Ok(())
+}
+
+fn save_snapshot(path: &str, width: usize, height: usize, rgb: &[u8]) {
+    if let Err(err) = snapshot::save_image(path, width, height, rgb) {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    }
+    println!("Saved the screen to {}", path);
+}
+
+// run an easy6502 program drawing its screen as it goes, Ctrl-C quits
+fn run_easy6502_live(cpu: &mut CPU<Easy6502>) {
+    let mut terminal = match Terminal::open() {
+        Ok(terminal) => terminal,
+        Err(err) => {
+            eprintln!("could not set up the terminal: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let result = cpu.run_with_callback(|cpu| match terminal.update(&mut cpu.bus) {
+        Ok(true) => {}
+        // Ctrl-C
+        Ok(false) => {
+            terminal.restore();
+            std::process::exit(130);
+        }
+        Err(err) => {
+            terminal.restore();
+            eprintln!("could not draw the screen: {}", err);
+            std::process::exit(1);
+        }
+    });
+    // whatever was drawn after the last full frame
+    let drawn = terminal.draw(&cpu.bus);
+    terminal.restore();
+    if let Err(err) = drawn {
+        eprintln!("could not draw the screen: {}", err);
+    }
+    if let Err(err) = result {
+        eprintln!("CPU stopped: {}", err);
+        std::process::exit(1);
+    }
+}
+
+// the 32x32 screen as colour numbers in hex, . for black
+fn print_screen(machine: &Easy6502) {
+    for y in 0..easy6502::SCREEN_SIZE {
+        let row: String = (0..easy6502::SCREEN_SIZE)
+            .map(|x| {
+                let color = machine.screen_color(x, y);
+                if color == 0 { '.' } else { std::char::from_digit(color as u32, 16).unwrap() }
+            })
+            .collect();
+        println!("{}", row);
+    }
+}
+
+// step through the program, printing registers and waiting for input after each instruction
+fn debug_session<M: Mem>(cpu: &mut CPU<M>) -> Result<(), cpu::CpuError> {
     use std::io::{stdin, stdout, Write};
-    use std::process;
     let mut cont_flag = 0;
-    let mut break_flag = 0;
     let mut s = String::new();
     println!("Please enter a character to continue (c to continue, s to step, z to exit): \n");
     // game cycle
-    cpu.run_with_callback(move |cpu| {
-        break_flag = 0;
+    loop {
+        let step = cpu.step()?;
+        println!("Instruction Received: {}", step.mnemonic);
+        if step.stop.is_some() {
+            break;
+        }
+
+        let mut break_flag = 0;
         print_regs(cpu);
         if cont_flag == 0 {
             while break_flag == 0 {
@@ -139,7 +439,7 @@ fn main() {
                     }
                     "z" => {
                         println!("done received");
-                        process::exit(0);
+                        return Ok(());
                     }
                     _ => {
                         println!("Invalid input...");
@@ -149,5 +449,6 @@ fn main() {
                 s.clear();
             }
         }
-    });
+    }
+    Ok(())
 }
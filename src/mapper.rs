@@ -0,0 +1,622 @@
+// cartridge boards: PRG/CHR bank switching behind the CPU's $4020-$FFFF and the PPU's pattern tables
+use crate::cartridge::{Mirroring, Rom, RomError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_BANK_SIZE: usize = 16384;
+const CHR_BANK_SIZE: usize = 8192;
+const CHR_RAM_SIZE: usize = 8192;
+const PRG_RAM: u16 = 0x6000;
+// trainers are loaded at $7000
+const TRAINER_OFFSET: usize = 0x1000;
+
+pub trait Mapper {
+    // CPU accesses to $4020-$FFFF
+    fn cpu_read(&mut self, addr: u16) -> u8;
+
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    // PPU accesses to the pattern tables at $0000-$1FFF
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // work RAM at $6000-$7FFF, empty when the board has none. Battery backed boards keep it in a save file
+    fn prg_ram(&self) -> &[u8];
+
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    // cpu cycles run since the last call, the bus only ticks between instructions
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+// the bus owns the mapper, the PPU keeps a handle to it for pattern table fetches
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+pub fn for_rom(rom: &Rom) -> Result<SharedMapper, RomError> {
+    let mapper: SharedMapper = match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(rom))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom))),
+        2 => Rc::new(RefCell::new(Uxrom::new(rom))),
+        3 => Rc::new(RefCell::new(Cnrom::new(rom))),
+        other => return Err(RomError::UnsupportedMapper(other)),
+    };
+    Ok(mapper)
+}
+
+// CHR-ROM, or 8 KiB of CHR-RAM for boards that ship without it
+struct Chr {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl Chr {
+    fn new(rom: &Rom) -> Self {
+        if rom.chr_rom.is_empty() {
+            Chr { data: vec![0; CHR_RAM_SIZE], writable: true }
+        } else {
+            Chr { data: rom.chr_rom.clone(), writable: false }
+        }
+    }
+
+    // read from a bank of bank_size bytes, bank numbers wrap around the available CHR
+    fn read(&self, bank: usize, bank_size: usize, offset: u16) -> u8 {
+        let index = (bank * bank_size + offset as usize % bank_size) % self.data.len();
+        self.data[index]
+    }
+
+    fn write(&mut self, bank: usize, bank_size: usize, offset: u16, data: u8) {
+        if self.writable {
+            let index = (bank * bank_size + offset as usize % bank_size) % self.data.len();
+            self.data[index] = data;
+        }
+    }
+}
+
+// work RAM at $6000-$7FFF, mirrored if smaller than 8 KiB
+struct PrgRam {
+    data: Vec<u8>,
+}
+
+impl PrgRam {
+    fn new(rom: &Rom) -> Self {
+        let mut data = vec![0; rom.prg_ram_size];
+        if let Some(trainer) = &rom.trainer {
+            if data.len() >= TRAINER_OFFSET + trainer.len() {
+                data[TRAINER_OFFSET..TRAINER_OFFSET + trainer.len()].copy_from_slice(trainer);
+            }
+        }
+        PrgRam { data }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
+        self.data[(addr - PRG_RAM) as usize % self.data.len()]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if !self.data.is_empty() {
+            let len = self.data.len();
+            self.data[(addr - PRG_RAM) as usize % len] = data;
+        }
+    }
+}
+
+// read from a 16 KiB PRG bank, bank numbers wrap around the available PRG-ROM
+fn prg_read(prg_rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let index = (bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))) % prg_rom.len();
+    prg_rom[index]
+}
+
+fn prg_banks(prg_rom: &[u8]) -> usize {
+    (prg_rom.len() / PRG_BANK_SIZE).max(1)
+}
+
+// mapper 0: 16 or 32 KiB of PRG-ROM, 8 KiB of CHR, no switching
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Chr,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: &Rom) -> Self {
+        Nrom {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: PrgRam::new(rom),
+            chr: Chr::new(rom),
+            mirroring: rom.mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(addr),
+            0x8000..=0xFFFF => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            self.prg_ram.write(addr, data);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram.data
+    }
+}
+
+// mapper 1: MMC1. Registers are loaded one bit at a time through a 5 bit shift register
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Chr,
+    shift: u8,
+    shift_count: u8,
+    // CPPMM: chr mode, prg mode, mirroring
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    cycles: u64,
+    // cycle count when the serial port was last written
+    last_write: Option<u64>,
+}
+
+impl Mmc1 {
+    pub fn new(rom: &Rom) -> Self {
+        Mmc1 {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: PrgRam::new(rom),
+            chr: Chr::new(rom),
+            shift: 0,
+            shift_count: 0,
+            // power on in "fix last bank at $C000" mode
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles: 0,
+            last_write: None,
+        }
+    }
+
+    // 16 KiB banks mapped at $8000 and $C000
+    fn prg_bank_pair(&self) -> (usize, usize) {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let last = prg_banks(&self.prg_rom) - 1;
+        match (self.control >> 2) & 0b11 {
+            // 32 KiB mode ignores the low bit
+            0 | 1 => (bank & !1, bank | 1),
+            // first bank fixed at $8000
+            2 => (0, bank),
+            // last bank fixed at $C000
+            _ => (bank, last),
+        }
+    }
+
+    // 4 KiB CHR bank for the given pattern table address
+    fn chr_bank(&self, addr: u16) -> usize {
+        if self.control & 0b1_0000 == 0 {
+            // 8 KiB mode ignores the low bit
+            (self.chr_bank_0 & !1) as usize + (addr >= 0x1000) as usize
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        }
+    }
+
+    // bit 4 of the PRG bank register switches work RAM off
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = data,
+            0xA000..=0xBFFF => self.chr_bank_0 = data,
+            0xC000..=0xDFFF => self.chr_bank_1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let (low, high) = self.prg_bank_pair();
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram.read(addr),
+            0x8000..=0xBFFF => prg_read(&self.prg_rom, low, addr),
+            0xC000..=0xFFFF => prg_read(&self.prg_rom, high, addr),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            if addr >= PRG_RAM && self.prg_ram_enabled() {
+                self.prg_ram.write(addr, data);
+            }
+            return;
+        }
+        // writes on back to back cycles only count once, the second write of a read-modify-write
+        // instruction is dropped. With no tick in between, both writes came from one instruction
+        if self.last_write == Some(self.cycles) {
+            return;
+        }
+        self.last_write = Some(self.cycles);
+        // bit 7 resets the shift register
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+        // bits arrive lsb first, the fifth write lands the value in the register picked by its address
+        self.shift |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            self.write_register(addr, self.shift);
+            self.shift = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), 0x1000, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_bank(addr);
+        self.chr.write(bank, 0x1000, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram.data
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+}
+
+// mapper 2: UxROM. Switchable 16 KiB bank at $8000, last bank fixed at $C000, CHR-RAM
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Chr,
+    mirroring: Mirroring,
+    prg_bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(rom: &Rom) -> Self {
+        Uxrom {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: PrgRam::new(rom),
+            chr: Chr::new(rom),
+            mirroring: rom.mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(addr),
+            0x8000..=0xBFFF => prg_read(&self.prg_rom, self.prg_bank, addr),
+            0xC000..=0xFFFF => prg_read(&self.prg_rom, prg_banks(&self.prg_rom) - 1, addr),
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+            0x8000..=0xFFFF => self.prg_bank = data as usize,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram.data
+    }
+}
+
+// mapper 3: CNROM. Fixed PRG like NROM, switchable 8 KiB CHR bank
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Chr,
+    mirroring: Mirroring,
+    chr_bank: usize,
+}
+
+impl Cnrom {
+    pub fn new(rom: &Rom) -> Self {
+        Cnrom {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: PrgRam::new(rom),
+            chr: Chr::new(rom),
+            mirroring: rom.mirroring,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(addr),
+            0x8000..=0xFFFF => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(addr, data),
+            0x8000..=0xFFFF => self.chr_bank = data as usize,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_bank;
+        self.chr.write(bank, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    fn rom(prg_pages: u8, chr_pages: u8, mapper: u8) -> Rom {
+        Rom::new(&test_rom(prg_pages, chr_pages, mapper << 4, mapper & 0xF0)).unwrap()
+    }
+
+    // one serial port write from its own STA, so a few cycles after the last one
+    fn mmc1_serial(mapper: &mut Mmc1, addr: u16, data: u8) {
+        mapper.tick(4);
+        mapper.cpu_write(addr, data);
+    }
+
+    // load an MMC1 register through the serial port, lsb first
+    fn mmc1_write(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1_serial(mapper, addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_for_rom() {
+        assert!(for_rom(&rom(1, 1, 0)).is_ok());
+        assert!(for_rom(&rom(1, 1, 3)).is_ok());
+        assert!(matches!(for_rom(&rom(1, 1, 4)), Err(RomError::UnsupportedMapper(4))));
+    }
+
+    #[test]
+    fn test_nrom_mirrors_16k() {
+        let mut mapper = Nrom::new(&rom(1, 1, 0));
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 0);
+        assert_eq!(mapper.ppu_read(0x0000), 0x80);
+        // CHR-ROM is read only
+        mapper.ppu_write(0x0000, 0x12);
+        assert_eq!(mapper.ppu_read(0x0000), 0x80);
+
+        let mut mapper = Nrom::new(&rom(2, 0, 0));
+        assert_eq!(mapper.cpu_read(0xC000), 1);
+        // no CHR-ROM means CHR-RAM
+        mapper.ppu_write(0x1234, 0x12);
+        assert_eq!(mapper.ppu_read(0x1234), 0x12);
+    }
+
+    #[test]
+    fn test_uxrom_bank_switching() {
+        let mut mapper = Uxrom::new(&rom(8, 0, 2));
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xBFFF), 3);
+        assert_eq!(mapper.cpu_read(0xFFFF), 7);
+    }
+
+    #[test]
+    fn test_cnrom_chr_switching() {
+        let mut mapper = Cnrom::new(&rom(2, 4, 3));
+        assert_eq!(mapper.ppu_read(0x0000), 0x80);
+        mapper.cpu_write(0xFFFF, 2);
+        assert_eq!(mapper.ppu_read(0x0000), 0x82);
+        assert_eq!(mapper.ppu_read(0x1FFF), 0x82);
+        assert_eq!(mapper.cpu_read(0xC000), 1);
+    }
+
+    #[test]
+    fn test_mmc1_prg_banking() {
+        let mut mapper = Mmc1::new(&rom(8, 2, 1));
+        // power on: last bank fixed at $C000
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+        mmc1_write(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+
+        // first bank fixed at $8000, switch $C000
+        mmc1_write(&mut mapper, 0x8000, 0b0_10_00);
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+
+        // 32 KiB mode drops the low bit of the bank number
+        mmc1_write(&mut mapper, 0x8000, 0b0_00_00);
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+    }
+
+    #[test]
+    fn test_mmc1_shift_register_reset() {
+        let mut mapper = Mmc1::new(&rom(8, 2, 1));
+        // three bits in, then a reset discards them
+        mmc1_serial(&mut mapper, 0xE000, 1);
+        mmc1_serial(&mut mapper, 0xE000, 1);
+        mmc1_serial(&mut mapper, 0xE000, 1);
+        mmc1_serial(&mut mapper, 0x8000, 0x80);
+        mmc1_write(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_mmc1_ignores_back_to_back_writes() {
+        let mut mapper = Mmc1::new(&rom(8, 2, 1));
+        // four bits, then a read-modify-write: only its first write (1) counts, the second (0) doesn't
+        for _ in 0..4 {
+            mmc1_serial(&mut mapper, 0xE000, 0);
+        }
+        mmc1_serial(&mut mapper, 0xE000, 1);
+        mapper.cpu_write(0xE000, 0);
+        // 0b10000 selects bank 0 and switches PRG-RAM off, a shifted in 0 would start the next value
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert!(!mapper.prg_ram_enabled());
+        mmc1_write(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+
+        // the same goes for resets
+        mmc1_serial(&mut mapper, 0xE000, 1);
+        mmc1_serial(&mut mapper, 0xE000, 0x80);
+        mapper.cpu_write(0xE000, 1);
+        mmc1_write(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mapper = Nrom::new(&rom(1, 1, 0));
+        mapper.cpu_write(0x6000, 0x12);
+        mapper.cpu_write(0x7FFF, 0x34);
+        assert_eq!(mapper.cpu_read(0x6000), 0x12);
+        assert_eq!(mapper.cpu_read(0x7FFF), 0x34);
+        assert_eq!(mapper.prg_ram().len(), 8192);
+        assert_eq!(mapper.prg_ram()[0x1FFF], 0x34);
+
+        let mut mapper = Uxrom::new(&rom(2, 0, 2));
+        mapper.prg_ram_mut()[0x10] = 0x56;
+        assert_eq!(mapper.cpu_read(0x6010), 0x56);
+        // RAM writes do not switch banks
+        mapper.cpu_write(0x6000, 1);
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+    }
+
+    #[test]
+    fn test_trainer_loaded_at_7000() {
+        let mapper = Nrom::new(&Rom::new(&test_rom(1, 1, 0b100, 0)).unwrap());
+        assert_eq!(mapper.prg_ram()[0x0FFF], 0x00);
+        assert_eq!(mapper.prg_ram()[0x1000], 0x77);
+        assert_eq!(mapper.prg_ram()[0x11FF], 0x77);
+    }
+
+    #[test]
+    fn test_mmc1_prg_ram_disable() {
+        let mut mapper = Mmc1::new(&rom(2, 1, 1));
+        mapper.cpu_write(0x6000, 0x12);
+        assert_eq!(mapper.cpu_read(0x6000), 0x12);
+        mmc1_write(&mut mapper, 0xE000, 0x10);
+        assert_eq!(mapper.cpu_read(0x6000), 0);
+        mapper.cpu_write(0x6000, 0x34);
+        mmc1_write(&mut mapper, 0xE000, 0x00);
+        assert_eq!(mapper.cpu_read(0x6000), 0x12);
+    }
+
+    #[test]
+    fn test_mmc1_chr_banking_and_mirroring() {
+        let mut mapper = Mmc1::new(&rom(2, 2, 1));
+        // 4 KiB CHR mode, vertical mirroring
+        mmc1_write(&mut mapper, 0x8000, 0b1_11_10);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        // 4 KiB bank 3 is the second half of 8 KiB page 1
+        mmc1_write(&mut mapper, 0xA000, 3);
+        mmc1_write(&mut mapper, 0xC000, 0);
+        assert_eq!(mapper.ppu_read(0x0000), 0x81);
+        assert_eq!(mapper.ppu_read(0x1000), 0x80);
+
+        // back to 8 KiB mode, horizontal mirroring
+        mmc1_write(&mut mapper, 0x8000, 0b0_11_11);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        mmc1_write(&mut mapper, 0xA000, 2);
+        assert_eq!(mapper.ppu_read(0x0000), 0x81);
+        assert_eq!(mapper.ppu_read(0x1FFF), 0x81);
+    }
+}
@@ -8,16 +8,26 @@ pub struct OpCode {
     pub length: u8,
     pub cycles: u8,
     pub mode: AddressingMode,
+    // undocumented NMOS opcode, only decoded when the cpu has them enabled
+    pub unofficial: bool,
 }
 
 impl OpCode {
     pub fn new(code: u8, mnemonic: &'static str, length: u8, cycles: u8, mode: AddressingMode) -> Self {
         OpCode {
-            code : code,
-            mnemonic : mnemonic,
-            length : length,
-            cycles : cycles,
-            mode : mode,
+            code,
+            mnemonic,
+            length,
+            cycles,
+            mode,
+            unofficial: false,
+        }
+    }
+
+    pub fn unofficial(code: u8, mnemonic: &'static str, length: u8, cycles: u8, mode: AddressingMode) -> Self {
+        OpCode {
+            unofficial: true,
+            ..OpCode::new(code, mnemonic, length, cycles, mode)
         }
     }
 }
@@ -135,8 +145,8 @@ lazy_static! {
 
         /* Branching */
 
-        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing), //AddressingMode that acts as Immidiate
-        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing), //AddressingMode:Indirect with 6502 bug
+        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::ABS),
+        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::IND), //Indirect with 6502 bug
 
         OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
         OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
@@ -218,6 +228,103 @@ lazy_static! {
         OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing),
         OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
         OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
+
+        /* Unofficial (stable NMOS opcodes) */
+        OpCode::unofficial(0xa7, "LAX", 2, 3, AddressingMode::ZP0),
+        OpCode::unofficial(0xb7, "LAX", 2, 4, AddressingMode::ZPY),
+        OpCode::unofficial(0xaf, "LAX", 3, 4, AddressingMode::ABS),
+        OpCode::unofficial(0xbf, "LAX", 3, 4/*+1 if page crossed*/, AddressingMode::ABY),
+        OpCode::unofficial(0xa3, "LAX", 2, 6, AddressingMode::IZX),
+        OpCode::unofficial(0xb3, "LAX", 2, 5/*+1 if page crossed*/, AddressingMode::IZY),
+
+        OpCode::unofficial(0x87, "SAX", 2, 3, AddressingMode::ZP0),
+        OpCode::unofficial(0x97, "SAX", 2, 4, AddressingMode::ZPY),
+        OpCode::unofficial(0x8f, "SAX", 3, 4, AddressingMode::ABS),
+        OpCode::unofficial(0x83, "SAX", 2, 6, AddressingMode::IZX),
+
+        OpCode::unofficial(0xc7, "DCP", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0xd7, "DCP", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0xcf, "DCP", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0xdf, "DCP", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0xdb, "DCP", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0xc3, "DCP", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0xd3, "DCP", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0xe7, "ISB", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0xf7, "ISB", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0xef, "ISB", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0xff, "ISB", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0xfb, "ISB", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0xe3, "ISB", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0xf3, "ISB", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0x07, "SLO", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0x17, "SLO", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0x0f, "SLO", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0x1f, "SLO", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0x1b, "SLO", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0x03, "SLO", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0x13, "SLO", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0x27, "RLA", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0x37, "RLA", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0x2f, "RLA", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0x3f, "RLA", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0x3b, "RLA", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0x23, "RLA", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0x33, "RLA", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0x47, "SRE", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0x57, "SRE", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0x4f, "SRE", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0x5f, "SRE", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0x5b, "SRE", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0x43, "SRE", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0x53, "SRE", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0x67, "RRA", 2, 5, AddressingMode::ZP0),
+        OpCode::unofficial(0x77, "RRA", 2, 6, AddressingMode::ZPX),
+        OpCode::unofficial(0x6f, "RRA", 3, 6, AddressingMode::ABS),
+        OpCode::unofficial(0x7f, "RRA", 3, 7, AddressingMode::ABX),
+        OpCode::unofficial(0x7b, "RRA", 3, 7, AddressingMode::ABY),
+        OpCode::unofficial(0x63, "RRA", 2, 8, AddressingMode::IZX),
+        OpCode::unofficial(0x73, "RRA", 2, 8, AddressingMode::IZY),
+
+        OpCode::unofficial(0x0b, "ANC", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x2b, "ANC", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x4b, "ALR", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x6b, "ARR", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0xcb, "SBX", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0xeb, "SBC", 2, 2, AddressingMode::IMM),
+
+        OpCode::unofficial(0x1a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::unofficial(0x3a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::unofficial(0x5a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::unofficial(0x7a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::unofficial(0xda, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::unofficial(0xfa, "NOP", 1, 2, AddressingMode::NoneAddressing),
+
+        OpCode::unofficial(0x80, "NOP", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x82, "NOP", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x89, "NOP", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0xc2, "NOP", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0xe2, "NOP", 2, 2, AddressingMode::IMM),
+        OpCode::unofficial(0x04, "NOP", 2, 3, AddressingMode::ZP0),
+        OpCode::unofficial(0x44, "NOP", 2, 3, AddressingMode::ZP0),
+        OpCode::unofficial(0x64, "NOP", 2, 3, AddressingMode::ZP0),
+        OpCode::unofficial(0x14, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0x34, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0x54, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0x74, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0xd4, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0xf4, "NOP", 2, 4, AddressingMode::ZPX),
+        OpCode::unofficial(0x0c, "NOP", 3, 4, AddressingMode::ABS),
+        OpCode::unofficial(0x1c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
+        OpCode::unofficial(0x3c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
+        OpCode::unofficial(0x5c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
+        OpCode::unofficial(0x7c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
+        OpCode::unofficial(0xdc, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
+        OpCode::unofficial(0xfc, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::ABX),
     ];
 
     // Create and return a hashmap
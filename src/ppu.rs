@@ -0,0 +1,553 @@
+// 2C02 picture processing unit, as seen through its eight registers at $2000-$2007
+use crate::bus::Device;
+use crate::cartridge::Mirroring;
+use crate::frame::Frame;
+use crate::mapper::SharedMapper;
+use crate::render;
+
+const VRAM_SIZE: usize = 4096;
+const PALETTE_SIZE: usize = 32;
+const OAM_SIZE: usize = 256;
+
+// frame timing, in dots and scanlines
+const DOTS_PER_LINE: u16 = 341;
+const VISIBLE_LINES: u16 = 240;
+const VBLANK_LINE: u16 = 241;
+const PRE_RENDER_LINE: u16 = 261;
+
+bitflags! {
+    // $2000
+    pub struct ControlRegister: u8 {
+        const NAMETABLE1 =              0b00000001;
+        const NAMETABLE2 =              0b00000010;
+        const VRAM_ADD_INCREMENT =      0b00000100;
+        const SPRITE_PATTERN_ADDR =     0b00001000;
+        const BACKGROUND_PATTERN_ADDR = 0b00010000;
+        const SPRITE_SIZE =             0b00100000;
+        const MASTER_SLAVE_SELECT =     0b01000000;
+        const GENERATE_NMI =            0b10000000;
+    }
+}
+
+bitflags! {
+    // $2001
+    pub struct MaskRegister: u8 {
+        const GREYSCALE =               0b00000001;
+        const LEFTMOST_8PXL_BACKGROUND = 0b00000010;
+        const LEFTMOST_8PXL_SPRITE =    0b00000100;
+        const SHOW_BACKGROUND =         0b00001000;
+        const SHOW_SPRITES =            0b00010000;
+        const EMPHASISE_RED =           0b00100000;
+        const EMPHASISE_GREEN =         0b01000000;
+        const EMPHASISE_BLUE =          0b10000000;
+    }
+}
+
+bitflags! {
+    // $2002, the low 5 bits read back whatever was last on the PPU data bus
+    pub struct StatusRegister: u8 {
+        const SPRITE_OVERFLOW =         0b00100000;
+        const SPRITE_ZERO_HIT =         0b01000000;
+        const VBLANK_STARTED =          0b10000000;
+    }
+}
+
+pub struct Ppu {
+    // pattern tables live on the cartridge, they read as 0 without one
+    mapper: Option<SharedMapper>,
+    pub vram: [u8; VRAM_SIZE],
+    pub palette: [u8; PALETTE_SIZE],
+    pub oam: [u8; OAM_SIZE],
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+    pub oam_addr: u8,
+    // current and temporary VRAM address, fine x scroll and the shared first/second write toggle
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+    // PPUDATA reads below the palette return the previous read
+    read_buffer: u8,
+    // value left on the data bus by the last register access
+    io_latch: u8,
+    // position of the beam
+    pub scanline: u16,
+    pub dot: u16,
+    odd_frame: bool,
+    // picture drawn so far, complete once vblank starts
+    pub frame: Frame,
+    // NMI raised and not yet picked up by the cpu
+    nmi_interrupt: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Ppu {
+    pub fn new(mapper: Option<SharedMapper>) -> Self {
+        Ppu {
+            mapper,
+            vram: [0; VRAM_SIZE],
+            palette: [0; PALETTE_SIZE],
+            oam: [0; OAM_SIZE],
+            ctrl: ControlRegister::empty(),
+            mask: MaskRegister::empty(),
+            status: StatusRegister::empty(),
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            frame: Frame::new(),
+            nmi_interrupt: false,
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match &self.mapper {
+            Some(mapper) => mapper.borrow().mirroring(),
+            None => Mirroring::Horizontal,
+        }
+    }
+
+    // index into vram for a nametable address in $2000-$3EFF
+    fn nametable_index(&self, addr: u16) -> usize {
+        let addr = (addr & 0x0FFF) as usize;
+        let table = addr / 0x400;
+        let physical = match self.mirroring() {
+            Mirroring::Vertical => table & 1,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        physical * 0x400 + (addr & 0x3FF)
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C are the background colour entries seen from the sprite side
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        if index & 0x13 == 0x10 {
+            index - 0x10
+        } else {
+            index
+        }
+    }
+
+    // read from the PPU address space
+    pub fn vram_read(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => match &self.mapper {
+                Some(mapper) => mapper.borrow_mut().ppu_read(addr),
+                None => 0,
+            },
+            0x2000..=0x3EFF => self.vram[self.nametable_index(addr)],
+            _ => self.palette[Self::palette_index(addr)],
+        }
+    }
+
+    // write to the PPU address space
+    pub fn vram_write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => {
+                if let Some(mapper) = &self.mapper {
+                    mapper.borrow_mut().ppu_write(addr, data);
+                }
+            }
+            0x2000..=0x3EFF => self.vram[self.nametable_index(addr)] = data,
+            _ => self.palette[Self::palette_index(addr)] = data,
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        let step = if self.ctrl.contains(ControlRegister::VRAM_ADD_INCREMENT) { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    pub fn write_ctrl(&mut self, data: u8) {
+        let nmi_was_enabled = self.ctrl.contains(ControlRegister::GENERATE_NMI);
+        self.ctrl = ControlRegister::from_bits_truncate(data);
+        // enabling NMI in the middle of vblank fires it straight away
+        if !nmi_was_enabled
+            && self.ctrl.contains(ControlRegister::GENERATE_NMI)
+            && self.status.contains(StatusRegister::VBLANK_STARTED)
+        {
+            self.nmi_interrupt = true;
+        }
+        // base nametable goes into bits 10-11 of t
+        self.t = (self.t & 0xF3FF) | (((data & 0b11) as u16) << 10);
+    }
+
+    pub fn write_mask(&mut self, data: u8) {
+        self.mask = MaskRegister::from_bits_truncate(data);
+    }
+
+    // reading the status ends vblank and resets the write toggle
+    pub fn read_status(&mut self) -> u8 {
+        let data = self.status.bits() | (self.io_latch & 0x1F);
+        self.status.remove(StatusRegister::VBLANK_STARTED);
+        self.w = false;
+        data
+    }
+
+    pub fn write_oam_addr(&mut self, data: u8) {
+        self.oam_addr = data;
+    }
+
+    pub fn read_oam_data(&self) -> u8 {
+        self.oam[self.oam_addr as usize]
+    }
+
+    pub fn write_oam_data(&mut self, data: u8) {
+        self.oam[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    // first write is coarse/fine x, second is coarse/fine y
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001F) | (data >> 3) as u16;
+            self.x = data & 0b111;
+        } else {
+            self.t = (self.t & !0x73E0) | (((data & 0b111) as u16) << 12) | (((data & 0xF8) as u16) << 2);
+        }
+        self.w = !self.w;
+    }
+
+    // first write is the high 6 bits, second the low byte which also copies t into v
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let addr = self.v & 0x3FFF;
+        let data = if addr >= 0x3F00 {
+            // palette reads are immediate, the buffer picks up the nametable byte underneath
+            self.read_buffer = self.vram_read(addr - 0x1000);
+            self.vram_read(addr)
+        } else {
+            let buffered = self.read_buffer;
+            self.read_buffer = self.vram_read(addr);
+            buffered
+        };
+        self.increment_vram_addr();
+        data
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.vram_write(self.v, data);
+        self.increment_vram_addr();
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+
+    // coarse x and horizontal nametable come back from t at the end of every visible line
+    pub fn copy_horizontal_bits(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    // fine y, coarse y and vertical nametable come back from t on the pre-render line
+    pub fn copy_vertical_bits(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    // move v down one pixel row, wrapping into the next nametable after row 29
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // rows 30 and 31 are attribute data, they wrap without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // whether an NMI was raised since the last call
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_interrupt)
+    }
+
+    // run for the given number of dots, 3 per cpu cycle. Returns true if vblank started along the way
+    pub fn tick(&mut self, dots: u64) -> bool {
+        let mut vblank_started = false;
+        for _ in 0..dots {
+            vblank_started |= self.step_dot();
+        }
+        vblank_started
+    }
+
+    fn step_dot(&mut self) -> bool {
+        let mut vblank_started = false;
+        let rendering = self.rendering_enabled();
+        match (self.scanline, self.dot) {
+            // visible lines are drawn in one go at the start of the line
+            (line, 1) if line < VISIBLE_LINES => {
+                let mut frame = std::mem::take(&mut self.frame);
+                render::render_scanline(self, &mut frame, line as usize);
+                self.frame = frame;
+            }
+            (line, 256) if line < VISIBLE_LINES && rendering => self.increment_y(),
+            (line, 257) if (line < VISIBLE_LINES || line == PRE_RENDER_LINE) && rendering => {
+                self.copy_horizontal_bits();
+            }
+            (VBLANK_LINE, 1) => {
+                self.status.insert(StatusRegister::VBLANK_STARTED);
+                if self.ctrl.contains(ControlRegister::GENERATE_NMI) {
+                    self.nmi_interrupt = true;
+                }
+                vblank_started = true;
+            }
+            (PRE_RENDER_LINE, 1) => {
+                self.status.remove(
+                    StatusRegister::VBLANK_STARTED | StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW,
+                );
+            }
+            (PRE_RENDER_LINE, 280) if rendering => self.copy_vertical_bits(),
+            _ => {}
+        }
+
+        self.dot += 1;
+        // odd frames are one dot shorter while rendering is on
+        if self.scanline == PRE_RENDER_LINE && self.dot == DOTS_PER_LINE - 1 && self.odd_frame && rendering {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > PRE_RENDER_LINE {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+        vblank_started
+    }
+}
+
+// registers are mirrored every 8 bytes across $2000-$3FFF
+impl Device for Ppu {
+    fn read(&mut self, offset: u16) -> u8 {
+        let data = match offset & 0b111 {
+            2 => self.read_status(),
+            4 => self.read_oam_data(),
+            7 => self.read_data(),
+            // write only registers read back the data bus
+            _ => self.io_latch,
+        };
+        self.io_latch = data;
+        data
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        self.io_latch = data;
+        match offset & 0b111 {
+            0 => self.write_ctrl(data),
+            1 => self.write_mask(data),
+            3 => self.write_oam_addr(data),
+            4 => self.write_oam_data(data),
+            5 => self.write_scroll(data),
+            6 => self.write_addr(data),
+            7 => self.write_data(data),
+            // status is read only
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Rom;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper;
+
+    // set PPUADDR through the register interface
+    fn set_addr(ppu: &mut Ppu, addr: u16) {
+        ppu.write(6, (addr >> 8) as u8);
+        ppu.write(6, addr as u8);
+    }
+
+    #[test]
+    fn test_vram_writes() {
+        let mut ppu = Ppu::default();
+        set_addr(&mut ppu, 0x2305);
+        ppu.write(7, 0x66);
+        assert_eq!(ppu.vram[0x0305], 0x66);
+        assert_eq!(ppu.v, 0x2306);
+    }
+
+    #[test]
+    fn test_read_buffer() {
+        let mut ppu = Ppu::default();
+        ppu.vram[0x0305] = 0x66;
+        ppu.vram[0x0306] = 0x77;
+        set_addr(&mut ppu, 0x2305);
+        // first read returns the stale buffer
+        ppu.read(7);
+        assert_eq!(ppu.read(7), 0x66);
+        assert_eq!(ppu.read(7), 0x77);
+    }
+
+    #[test]
+    fn test_increment_by_32() {
+        let mut ppu = Ppu::default();
+        ppu.write(0, 0b100);
+        ppu.vram[0x01ff] = 0x66;
+        ppu.vram[0x01ff + 32] = 0x77;
+        set_addr(&mut ppu, 0x21ff);
+        ppu.read(7);
+        assert_eq!(ppu.read(7), 0x66);
+        assert_eq!(ppu.read(7), 0x77);
+        assert_eq!(ppu.v, 0x21ff + 96);
+    }
+
+    #[test]
+    fn test_palette_reads_are_unbuffered() {
+        let mut ppu = Ppu::default();
+        // $2F10 with horizontal mirroring
+        ppu.vram[0x0710] = 0x12;
+        set_addr(&mut ppu, 0x3F00);
+        ppu.write(7, 0x0F);
+        // $3F10 mirrors $3F00
+        set_addr(&mut ppu, 0x3F10);
+        assert_eq!(ppu.read(7), 0x0F);
+        // the buffer holds the nametable byte under the palette
+        set_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.read(7), 0x12);
+    }
+
+    #[test]
+    fn test_horizontal_mirroring() {
+        let mut ppu = Ppu::default();
+        set_addr(&mut ppu, 0x2405);
+        ppu.write(7, 0x66);
+        set_addr(&mut ppu, 0x2805);
+        ppu.write(7, 0x77);
+
+        set_addr(&mut ppu, 0x2005);
+        ppu.read(7);
+        assert_eq!(ppu.read(7), 0x66);
+        set_addr(&mut ppu, 0x2C05);
+        ppu.read(7);
+        assert_eq!(ppu.read(7), 0x77);
+    }
+
+    #[test]
+    fn test_vertical_mirroring_and_chr() {
+        let rom = Rom::new(&test_rom(1, 1, 0b1, 0)).unwrap();
+        let mut ppu = Ppu::new(Some(mapper::for_rom(&rom).unwrap()));
+        set_addr(&mut ppu, 0x2805);
+        ppu.write(7, 0x66);
+        assert_eq!(ppu.vram[0x0005], 0x66);
+
+        // pattern tables come from the cartridge
+        set_addr(&mut ppu, 0x0010);
+        ppu.read(7);
+        assert_eq!(ppu.read(7), 0x80);
+    }
+
+    #[test]
+    fn test_status_clears_vblank_and_toggle() {
+        let mut ppu = Ppu::default();
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write(6, 0x21);
+        assert!(ppu.w);
+        assert_eq!(ppu.read(2) & 0x80, 0x80);
+        assert_eq!(ppu.read(2) & 0x80, 0);
+        assert!(!ppu.w);
+        // the write toggle was reset, so this is a fresh high byte
+        set_addr(&mut ppu, 0x2305);
+        assert_eq!(ppu.v, 0x2305);
+    }
+
+    #[test]
+    fn test_scroll_registers() {
+        let mut ppu = Ppu::default();
+        ppu.write(0, 0b10);
+        // x = 125: coarse 15, fine 5, y = 94: coarse 11, fine 6, nametable 2 -> yyy NN YYYYY XXXXX
+        ppu.write(5, 125);
+        ppu.write(5, 94);
+        assert_eq!(ppu.x, 5);
+        assert_eq!(ppu.t, 0b110_1001_0110_1111);
+    }
+
+    #[test]
+    fn test_oam_data() {
+        let mut ppu = Ppu::default();
+        ppu.write(3, 0x10);
+        ppu.write(4, 0x66);
+        ppu.write(4, 0x77);
+        ppu.write(3, 0x10);
+        assert_eq!(ppu.read(4), 0x66);
+        ppu.write(3, 0x11);
+        assert_eq!(ppu.read(4), 0x77);
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut ppu = Ppu::default();
+        ppu.write(0, 0x80);
+        // vblank starts at dot 1 of line 241
+        assert!(!ppu.tick(241 * 341 + 1));
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.tick(1));
+        assert!(ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        // and ends on the pre-render line
+        ppu.tick(20 * 341);
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert_eq!((ppu.scanline, ppu.dot), (261, 2));
+        ppu.tick(339);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    }
+
+    #[test]
+    fn test_enabling_nmi_during_vblank() {
+        let mut ppu = Ppu::default();
+        ppu.tick(241 * 341 + 2);
+        assert!(!ppu.poll_nmi());
+        ppu.write(0, 0x80);
+        assert!(ppu.poll_nmi());
+        // already enabled, writing again does nothing
+        ppu.write(0, 0x80);
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_register_mirrors() {
+        let mut ppu = Ppu::default();
+        // $3456 is $2006
+        ppu.write(0x1456, 0x23);
+        ppu.write(0x1456, 0x05);
+        assert_eq!(ppu.v, 0x2305);
+    }
+}
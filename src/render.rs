@@ -0,0 +1,309 @@
+// scanline renderer: background from the nametables at the current scroll, then up to 8 sprites per line
+use crate::frame::{Frame, HEIGHT, WIDTH};
+use crate::palette::SYSTEM_PALETTE;
+use crate::ppu::{ControlRegister, MaskRegister, Ppu, StatusRegister};
+
+const SPRITES_PER_LINE: usize = 8;
+
+// colour for an entry of palette RAM
+fn color(ppu: &mut Ppu, entry: u16) -> (u8, u8, u8) {
+    let mut index = ppu.vram_read(0x3F00 + entry) & 0x3F;
+    if ppu.mask.contains(MaskRegister::GREYSCALE) {
+        index &= 0x30;
+    }
+    SYSTEM_PALETTE[index as usize]
+}
+
+// 2 bit pixel value of a tile row, x counted from the left edge of the tile
+fn tile_pixel(low: u8, high: u8, x: u8) -> u8 {
+    let bit = 7 - x;
+    (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+}
+
+// draw the background for one line starting from v, returns which pixels were opaque
+fn render_background(ppu: &mut Ppu, frame: &mut Frame, scanline: usize) -> [bool; WIDTH] {
+    let mut opaque = [false; WIDTH];
+    let backdrop = color(ppu, 0);
+    if !ppu.mask.contains(MaskRegister::SHOW_BACKGROUND) {
+        for x in 0..WIDTH {
+            frame.set_pixel(x, scanline, backdrop);
+        }
+        return opaque;
+    }
+
+    let bank: u16 = if ppu.ctrl.contains(ControlRegister::BACKGROUND_PATTERN_ADDR) { 0x1000 } else { 0 };
+    let fine_y = (ppu.v >> 12) & 0b111;
+    let fine_x = ppu.x as isize;
+    let mut v = ppu.v;
+    // fine x scroll means 33 tiles can touch the line
+    for tile in 0..33 {
+        let tile_index = ppu.vram_read(0x2000 | (v & 0x0FFF)) as u16;
+        let attribute = ppu.vram_read(0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+        // each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+        let shift = ((v >> 4) & 0b100) | (v & 0b10);
+        let palette = ((attribute >> shift) & 0b11) as u16;
+        let low = ppu.vram_read(bank + tile_index * 16 + fine_y);
+        let high = ppu.vram_read(bank + tile_index * 16 + fine_y + 8);
+
+        for px in 0..8 {
+            let x = tile * 8 + px as isize - fine_x;
+            if x < 0 || x >= WIDTH as isize {
+                continue;
+            }
+            let x = x as usize;
+            let mut value = tile_pixel(low, high, px);
+            if x < 8 && !ppu.mask.contains(MaskRegister::LEFTMOST_8PXL_BACKGROUND) {
+                value = 0;
+            }
+            let rgb = if value == 0 { backdrop } else { color(ppu, palette * 4 + value as u16) };
+            frame.set_pixel(x, scanline, rgb);
+            opaque[x] = value != 0;
+        }
+
+        // coarse x increment, switching horizontal nametable after column 31
+        if v & 0x001F == 31 {
+            v = (v & !0x001F) ^ 0x0400;
+        } else {
+            v += 1;
+        }
+    }
+    opaque
+}
+
+// draw the sprites in range of this line over the background, flagging overflow and sprite zero hits
+fn render_sprites(ppu: &mut Ppu, frame: &mut Frame, scanline: usize, background: &[bool; WIDTH]) {
+    let tall = ppu.ctrl.contains(ControlRegister::SPRITE_SIZE);
+    let height: isize = if tall { 16 } else { 8 };
+
+    // sprite evaluation, OAM y is one line above where the sprite shows up
+    let mut selected = Vec::with_capacity(SPRITES_PER_LINE);
+    for sprite in 0..64 {
+        let row = scanline as isize - 1 - ppu.oam[sprite * 4] as isize;
+        if row < 0 || row >= height {
+            continue;
+        }
+        if selected.len() == SPRITES_PER_LINE {
+            ppu.status.insert(StatusRegister::SPRITE_OVERFLOW);
+            break;
+        }
+        selected.push((sprite, row as u16));
+    }
+
+    if !ppu.mask.contains(MaskRegister::SHOW_SPRITES) {
+        return;
+    }
+
+    // lower OAM index wins where sprites overlap, even if it ends up behind the background
+    let mut drawn = [false; WIDTH];
+    for (sprite, row) in selected {
+        let tile = ppu.oam[sprite * 4 + 1] as u16;
+        let attributes = ppu.oam[sprite * 4 + 2];
+        let left = ppu.oam[sprite * 4 + 3] as usize;
+        let palette = (attributes & 0b11) as u16;
+        let behind_background = attributes & 0x20 != 0;
+        let flip_horizontal = attributes & 0x40 != 0;
+        let row = if attributes & 0x80 != 0 { height as u16 - 1 - row } else { row };
+
+        let address = if tall {
+            // 8x16 sprites pick their pattern table with bit 0 of the tile number
+            let bank = (tile & 1) * 0x1000;
+            let tile = (tile & 0xFE) + row / 8;
+            bank + tile * 16 + row % 8
+        } else {
+            let bank: u16 = if ppu.ctrl.contains(ControlRegister::SPRITE_PATTERN_ADDR) { 0x1000 } else { 0 };
+            bank + tile * 16 + row
+        };
+        let low = ppu.vram_read(address);
+        let high = ppu.vram_read(address + 8);
+
+        for px in 0..8u8 {
+            let x = left + px as usize;
+            if x >= WIDTH || drawn[x] {
+                continue;
+            }
+            let value = tile_pixel(low, high, if flip_horizontal { 7 - px } else { px });
+            if value == 0 || (x < 8 && !ppu.mask.contains(MaskRegister::LEFTMOST_8PXL_SPRITE)) {
+                continue;
+            }
+            drawn[x] = true;
+            if sprite == 0 && background[x] && x != 255 {
+                ppu.status.insert(StatusRegister::SPRITE_ZERO_HIT);
+            }
+            if !behind_background || !background[x] {
+                let rgb = color(ppu, 0x10 + palette * 4 + value as u16);
+                frame.set_pixel(x, scanline, rgb);
+            }
+        }
+    }
+}
+
+// render one visible line from the PPU's current state
+pub fn render_scanline(ppu: &mut Ppu, frame: &mut Frame, scanline: usize) {
+    let background = render_background(ppu, frame, scanline);
+    if ppu.rendering_enabled() {
+        render_sprites(ppu, frame, scanline, &background);
+    }
+}
+
+// render a whole frame in one go, walking v through the scroll the way the PPU does line by line
+pub fn render_frame(ppu: &mut Ppu, frame: &mut Frame) {
+    ppu.status.remove(StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW);
+    let enabled = ppu.rendering_enabled();
+    if enabled {
+        ppu.copy_vertical_bits();
+    }
+    for scanline in 0..HEIGHT {
+        if enabled {
+            ppu.copy_horizontal_bits();
+        }
+        render_scanline(ppu, frame, scanline);
+        if enabled {
+            ppu.increment_y();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Rom;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper;
+
+    const RED: (u8, u8, u8) = SYSTEM_PALETTE[0x16];
+    const BLUE: (u8, u8, u8) = SYSTEM_PALETTE[0x12];
+    const GREEN: (u8, u8, u8) = SYSTEM_PALETTE[0x1A];
+    const BLACK: (u8, u8, u8) = SYSTEM_PALETTE[0x0F];
+
+    // PPU on an NROM board with CHR-RAM. Tile 1 is solid colour 1, tile 2 solid colour 3,
+    // tile 3 has only its leftmost column set
+    fn test_ppu() -> Ppu {
+        let rom = Rom::new(&test_rom(1, 0, 0, 0)).unwrap();
+        let mut ppu = Ppu::new(Some(mapper::for_rom(&rom).unwrap()));
+        for row in 0..8 {
+            ppu.vram_write(16 + row, 0xFF);
+            ppu.vram_write(32 + row, 0xFF);
+            ppu.vram_write(32 + row + 8, 0xFF);
+            ppu.vram_write(48 + row, 0x80);
+        }
+        ppu.vram_write(0x3F00, 0x0F);
+        ppu.vram_write(0x3F01, 0x16);
+        ppu.vram_write(0x3F03, 0x12);
+        ppu.vram_write(0x3F11, 0x1A);
+        // background and sprites on, including the leftmost 8 pixels
+        ppu.mask = MaskRegister::from_bits_truncate(0b0001_1110);
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut Ppu, sprite: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+        ppu.oam[sprite * 4..sprite * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    #[test]
+    fn test_backdrop_when_disabled() {
+        let mut ppu = test_ppu();
+        ppu.mask = MaskRegister::empty();
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        assert_eq!(frame.pixel(0, 0), BLACK);
+        assert_eq!(frame.pixel(255, 239), BLACK);
+    }
+
+    #[test]
+    fn test_background_tiles_and_attributes() {
+        let mut ppu = test_ppu();
+        ppu.vram_write(0x2000, 1);
+        ppu.vram_write(0x2001, 2);
+        // bottom right quadrant of the first attribute byte uses palette 1
+        ppu.vram_write(0x2042, 2);
+        ppu.vram_write(0x23C0, 0b01_00_00_00);
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        assert_eq!(frame.pixel(0, 0), RED);
+        assert_eq!(frame.pixel(7, 7), RED);
+        assert_eq!(frame.pixel(8, 0), BLUE);
+        assert_eq!(frame.pixel(16, 0), BLACK);
+        assert_eq!(frame.pixel(0, 8), BLACK);
+        // colour 3 of palette 1 is $3F07, left at 0
+        assert_eq!(frame.pixel(16, 16), SYSTEM_PALETTE[0]);
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut ppu = test_ppu();
+        ppu.vram_write(0x2001, 1);
+        // scroll 4 pixels right and 8 down, the tile in row 0 goes off screen
+        ppu.write_scroll(4);
+        ppu.write_scroll(8);
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        assert_eq!(frame.pixel(4, 0), BLACK);
+
+        ppu.write_scroll(4);
+        ppu.write_scroll(0);
+        render_frame(&mut ppu, &mut frame);
+        assert_eq!(frame.pixel(3, 0), BLACK);
+        assert_eq!(frame.pixel(4, 0), RED);
+        assert_eq!(frame.pixel(11, 7), RED);
+        assert_eq!(frame.pixel(12, 0), BLACK);
+    }
+
+    #[test]
+    fn test_sprite_priority_and_flip() {
+        let mut ppu = test_ppu();
+        ppu.vram_write(0x2000, 1);
+        ppu.vram_write(0x2020, 1);
+        // in front of the background, showing from line 1
+        set_sprite(&mut ppu, 0, 0, 1, 0, 0);
+        // behind the background, only shows over the backdrop
+        set_sprite(&mut ppu, 1, 10, 1, 0x20, 4);
+        // flipped horizontally, its only column ends up on the right
+        set_sprite(&mut ppu, 2, 20, 3, 0x40, 100);
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        assert_eq!(frame.pixel(0, 0), RED);
+        assert_eq!(frame.pixel(0, 1), GREEN);
+        assert_eq!(frame.pixel(7, 1), GREEN);
+        assert_eq!(frame.pixel(8, 1), BLACK);
+        assert_eq!(frame.pixel(5, 11), RED);
+        assert_eq!(frame.pixel(9, 11), GREEN);
+        assert_eq!(frame.pixel(12, 11), BLACK);
+        assert_eq!(frame.pixel(100, 21), BLACK);
+        assert_eq!(frame.pixel(107, 21), GREEN);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = test_ppu();
+        set_sprite(&mut ppu, 0, 50, 1, 0, 50);
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        // nothing opaque behind it
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        ppu.vram_write(0x2000 + 6 * 32 + 6, 1);
+        render_frame(&mut ppu, &mut frame);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = test_ppu();
+        for sprite in 0..64 {
+            set_sprite(&mut ppu, sprite, 0xFF, 1, 0, 0);
+        }
+        for sprite in 0..8 {
+            set_sprite(&mut ppu, sprite, 100, 1, 0, sprite as u8 * 10);
+        }
+        let mut frame = Frame::new();
+        render_frame(&mut ppu, &mut frame);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        set_sprite(&mut ppu, 8, 104, 1, 0, 200);
+        render_frame(&mut ppu, &mut frame);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+        // the ninth sprite is dropped on the lines it shares, drawn below them
+        assert_eq!(frame.pixel(200, 106), BLACK);
+        assert_eq!(frame.pixel(200, 110), GREEN);
+    }
+}
@@ -0,0 +1,217 @@
+// pictures of what a program drew, as binary PPM or PNG files. Both are written out by hand:
+// PPM is a header and the raw pixels, PNG uses uncompressed deflate blocks so it needs
+// nothing more than the two checksums
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// the most a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// rgb holds width * height pixels, 3 bytes each, row by row
+pub fn write_ppm<W: Write>(mut out: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    check_size(width, height, rgb)?;
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    out.write_all(rgb)?;
+    out.flush()
+}
+
+pub fn write_png<W: Write>(mut out: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    check_size(width, height, rgb)?;
+    out.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour, then deflate, adaptive filtering and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header)?;
+
+    // every row starts with its filter type, 0 leaves the pixels as they are
+    let mut scanlines = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks(width * 3) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+// PNG for a .png path, PPM for anything else
+pub fn save_image<P: AsRef<Path>>(path: P, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let out = BufWriter::new(File::create(path)?);
+    let png = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    if png {
+        write_png(out, width, height, rgb)
+    } else {
+        write_ppm(out, width, height, rgb)
+    }
+}
+
+fn check_size(width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    if width.checked_mul(height).and_then(|pixels| pixels.checked_mul(3)) != Some(rgb.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}x{} image doesn't match {} bytes of pixels", width, height, rgb.len()),
+        ));
+    }
+    Ok(())
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    // the checksum covers the type as well as the data
+    let crc = !update_crc32(update_crc32(0xFFFF_FFFF, kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+// a zlib stream that doesn't compress: deflate blocks of type 0, each just a length and the bytes
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, no preset dictionary, check bits so the header is a multiple of 31
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // even nothing needs a final block
+        stream.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        stream.push(last as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+// the reflected CRC-32 PNG uses, one bit at a time. Start from 0xFFFFFFFF and invert the result
+fn update_crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % MODULUS;
+        b = (b + a) % MODULUS;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::easy6502::{Easy6502, SCREEN_SIZE};
+    use crate::frame::{Frame, HEIGHT, WIDTH};
+
+    // pull the pixels back out of one of our PNGs, only understands stored blocks
+    fn decode_png(png: &[u8]) -> (usize, usize, Vec<u8>) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let mut position = 8;
+        let (mut width, mut height, mut idat) = (0, 0, vec![]);
+        loop {
+            let length = u32::from_be_bytes([png[position], png[position + 1], png[position + 2], png[position + 3]]) as usize;
+            let kind = &png[position + 4..position + 8];
+            let data = &png[position + 8..position + 8 + length];
+            let crc = &png[position + 8 + length..position + 12 + length];
+            assert_eq!(crc, (!update_crc32(update_crc32(0xFFFF_FFFF, kind), data)).to_be_bytes());
+            match kind {
+                b"IHDR" => {
+                    width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                    height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+                }
+                b"IDAT" => idat.extend_from_slice(data),
+                b"IEND" => break,
+                _ => panic!("unexpected chunk"),
+            }
+            position += 12 + length;
+        }
+
+        assert_eq!((idat[0] as u16 * 256 + idat[1] as u16) % 31, 0);
+        let mut inflated = vec![];
+        let mut position = 2;
+        loop {
+            let last = idat[position] & 1 == 1;
+            let length = u16::from_le_bytes([idat[position + 1], idat[position + 2]]);
+            assert_eq!(!length, u16::from_le_bytes([idat[position + 3], idat[position + 4]]));
+            inflated.extend_from_slice(&idat[position + 5..position + 5 + length as usize]);
+            position += 5 + length as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(idat[position..], adler32(&inflated).to_be_bytes());
+
+        let rgb = inflated
+            .chunks(width * 3 + 1)
+            .flat_map(|row| {
+                assert_eq!(row[0], 0);
+                row[1..].to_vec()
+            })
+            .collect();
+        (width, height, rgb)
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(!update_crc32(0xFFFF_FFFF, b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_easy6502_screen_ppm() {
+        let mut cpu = CPU::new(Easy6502::new());
+        // test.asm, three pixels along the top
+        cpu.load_run(vec![0xa9, 0x01, 0x8d, 0x00, 0x02, 0xa9, 0x05, 0x8d, 0x01, 0x02, 0xa9, 0x08, 0x8d, 0x02, 0x02])
+            .unwrap();
+        let mut ppm = vec![];
+        write_ppm(&mut ppm, SCREEN_SIZE, SCREEN_SIZE, &cpu.bus.screen_rgb()).unwrap();
+
+        let mut golden = b"P6\n32 32\n255\n".to_vec();
+        golden.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x00, 0xCC, 0x55, 0xDD, 0x88, 0x55]);
+        golden.resize(golden.len() + (32 * 32 - 3) * 3, 0);
+        assert_eq!(ppm, golden);
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let mut cpu = CPU::new(Easy6502::new());
+        cpu.load_run(vec![0xa9, 0x0e, 0x8d, 0xff, 0x05]).unwrap();
+        let mut png = vec![];
+        write_png(&mut png, SCREEN_SIZE, SCREEN_SIZE, &cpu.bus.screen_rgb()).unwrap();
+        assert_eq!(decode_png(&png), (SCREEN_SIZE, SCREEN_SIZE, cpu.bus.screen_rgb()));
+
+        // a whole PPU frame needs several stored blocks
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        frame.set_pixel(WIDTH - 1, HEIGHT - 1, (4, 5, 6));
+        let mut png = vec![];
+        write_png(&mut png, WIDTH, HEIGHT, &frame.data).unwrap();
+        assert_eq!(decode_png(&png), (WIDTH, HEIGHT, frame.data));
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut out = vec![];
+        let err = write_ppm(&mut out, 2, 2, &[0; 11]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = write_png(&mut out, 2, 2, &[0; 13]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // nothing written before the check
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+// draws the easy6502 screen straight into an ANSI truecolor terminal and reads keys from it,
+// for machines without a window system. Each character cell holds two pixels stacked on top
+// of each other: the upper half block in the foreground colour over the background colour
+use crate::easy6502::{Easy6502, SCREEN_SIZE};
+use std::io::{self, Read, Write};
+use std::panic;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+// raw mode turns off signals, so Ctrl-C arrives as a key like any other
+pub const CTRL_C: u8 = 0x03;
+
+const UPPER_HALF_BLOCK: char = '▀';
+
+// one frame of the screen, starting from the top left corner of the terminal
+pub fn draw_screen<W: Write>(mut out: W, machine: &Easy6502) -> io::Result<()> {
+    // build it all first so the terminal never shows half a frame
+    let mut text = String::from("\x1b[H");
+    for y in (0..SCREEN_SIZE).step_by(2) {
+        for x in 0..SCREEN_SIZE {
+            let (top_r, top_g, top_b) = machine.pixel(x, y);
+            let (bottom_r, bottom_g, bottom_b) = machine.pixel(x, y + 1);
+            text.push_str(&format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m{}",
+                top_r, top_g, top_b, bottom_r, bottom_g, bottom_b, UPPER_HALF_BLOCK
+            ));
+        }
+        // raw mode doesn't turn \n into \r\n
+        text.push_str("\x1b[0m\r\n");
+    }
+    out.write_all(text.as_bytes())?;
+    out.flush()
+}
+
+// what the live loop does once the cpu has run a frame: hand the keys pressed to the program
+// and redraw if the screen changed. Doing it more often only slows the program down.
+// keys is only asked for at the end of a frame. Returns false when Ctrl-C was pressed
+pub fn update_frame<W, K>(out: W, machine: &mut Easy6502, keys: K) -> io::Result<bool>
+where
+    W: Write,
+    K: FnOnce() -> Vec<u8>,
+{
+    if !machine.take_frame_complete() {
+        return Ok(true);
+    }
+    for key in keys() {
+        if key == CTRL_C {
+            return Ok(false);
+        }
+        machine.set_key(key);
+    }
+    if machine.take_screen_changed() {
+        draw_screen(out, machine)?;
+    }
+    Ok(true)
+}
+
+// the terminal in raw mode, restored when dropped. Keys are read on a thread of their own
+// so the program never waits for one.
+// Drop doesn't run when a panic aborts, so a panic hook restores the terminal as well
+pub struct Terminal {
+    keys: Receiver<u8>,
+}
+
+// stty settings from before raw mode, None once restored. Global so the panic hook can get at them
+static SAVED_MODE: Mutex<Option<String>> = Mutex::new(None);
+// the hook is installed by the first open and stays, later terminals reuse it
+static PANIC_HOOK: Once = Once::new();
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    // stty works on its stdin, which has to be the terminal
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// show the cursor again and put the stty settings back, once
+fn restore_mode() {
+    // a panic while holding the lock still leaves the settings to restore
+    let mut saved_mode = SAVED_MODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(saved_mode) = saved_mode.take() {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x1b[0m\x1b[?25h");
+        let _ = stdout.flush();
+        let _ = stty(&[&saved_mode]);
+    }
+}
+
+impl Terminal {
+    pub fn open() -> io::Result<Terminal> {
+        let saved_mode = stty(&["-g"])?;
+        *SAVED_MODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(saved_mode);
+        stty(&["raw", "-echo"])?;
+
+        // restore before the panic message is printed, raw mode would garble it
+        PANIC_HOOK.call_once(|| {
+            let previous_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                restore_mode();
+                previous_hook(info);
+            }));
+        });
+
+        let (sender, keys) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut key = [0u8; 1];
+            while let Ok(1) = stdin.read(&mut key) {
+                if sender.send(key[0]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // clear the screen and hide the cursor
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[2J\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Terminal { keys })
+    }
+
+    // keys pressed since the last call, oldest first
+    pub fn keys(&self) -> Vec<u8> {
+        self.keys.try_iter().collect()
+    }
+
+    pub fn draw(&self, machine: &Easy6502) -> io::Result<()> {
+        draw_screen(io::stdout().lock(), machine)
+    }
+
+    // see update_frame, with this terminal's keys and screen
+    pub fn update(&self, machine: &mut Easy6502) -> io::Result<bool> {
+        update_frame(io::stdout().lock(), machine, || self.keys())
+    }
+
+    // back to the way the terminal was, also done on drop and on panic
+    pub fn restore(&mut self) {
+        restore_mode();
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+    use crate::easy6502::{CYCLES_PER_FRAME, LAST_KEY, SCREEN};
+
+    #[test]
+    fn test_draw_screen() {
+        let mut machine = Easy6502::new();
+        // white at (0, 0), green at (0, 1), red at (31, 31)
+        machine.mem_write(SCREEN, 0x01);
+        machine.mem_write(SCREEN + 32, 0x05);
+        machine.mem_write(SCREEN + 32 * 32 - 1, 0x02);
+
+        let mut out = vec![];
+        draw_screen(&mut out, &machine).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("\x1b[H\x1b[38;2;255;255;255m\x1b[48;2;0;204;85m▀\x1b[38;2;0;0;0m"));
+        assert_eq!(text.matches(UPPER_HALF_BLOCK).count(), 32 * 16);
+        assert_eq!(text.matches("\r\n").count(), 16);
+        assert!(text.ends_with("\x1b[38;2;0;0;0m\x1b[48;2;136;0;0m▀\x1b[0m\r\n"));
+    }
+
+    #[test]
+    fn test_update_frame() {
+        let mut machine = Easy6502::new();
+        let mut out = vec![];
+        // mid frame nothing happens, keys stay queued for later
+        machine.mem_write(SCREEN, 0x01);
+        let result = update_frame(&mut out, &mut machine, || panic!("keys read mid frame"));
+        assert!(result.unwrap());
+        assert!(out.is_empty());
+
+        // end of a frame with the screen changed: keys delivered, the last one wins, and a redraw
+        machine.tick(CYCLES_PER_FRAME);
+        assert!(update_frame(&mut out, &mut machine, || vec![b'w', b'd']).unwrap());
+        assert_eq!(machine.mem_read(LAST_KEY), b'd');
+        assert!(out.starts_with(b"\x1b[H"));
+
+        // a frame that didn't touch the screen isn't drawn again
+        out.clear();
+        machine.tick(CYCLES_PER_FRAME);
+        assert!(update_frame(&mut out, &mut machine, Vec::new).unwrap());
+        assert!(out.is_empty());
+
+        // Ctrl-C quits and nothing after it counts
+        machine.tick(CYCLES_PER_FRAME);
+        assert!(!update_frame(&mut out, &mut machine, || vec![CTRL_C, b's']).unwrap());
+        assert_eq!(machine.mem_read(LAST_KEY), b'd');
+    }
+}
@@ -0,0 +1,94 @@
+// 16 bit mono PCM WAV files, for listening to (or diffing) what the APU produced
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+
+// samples are expected in -1.0..=1.0, anything outside is clipped
+pub fn write_wav<W: Write>(mut out: W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_size = samples.len() as u32 * block_align as u32;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // 1 = uncompressed PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&pcm.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+// one pole high-pass that takes out the DC offset, like the 90 Hz filter after the NES DAC.
+// Unsigned output such as the APU's 0.0..1.0 comes out centred on 0
+pub fn remove_dc(sample_rate: u32, samples: &[f32]) -> Vec<f32> {
+    const CUTOFF: f32 = 90.0;
+    let rc = 1.0 / (2.0 * std::f32::consts::PI * CUTOFF);
+    let alpha = rc / (rc + 1.0 / sample_rate as f32);
+    let (mut last_in, mut last_out) = (0.0, 0.0);
+    samples
+        .iter()
+        .map(|&sample| {
+            last_out = alpha * (last_out + sample - last_in);
+            last_in = sample;
+            last_out
+        })
+        .collect()
+}
+
+pub fn save_wav<P: AsRef<Path>>(path: P, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    write_wav(BufWriter::new(File::create(path)?), sample_rate, samples)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wav_layout() {
+        let mut out = vec![];
+        write_wav(&mut out, 44100, &[0.0, 1.0, -2.0]).unwrap();
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([out[4], out[5], out[6], out[7]]), 36 + 6);
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes([out[24], out[25], out[26], out[27]]), 44100);
+        assert_eq!(u32::from_le_bytes([out[28], out[29], out[30], out[31]]), 88200);
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(u32::from_le_bytes([out[40], out[41], out[42], out[43]]), 6);
+        // 0, full scale, clipped
+        assert_eq!(&out[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn test_remove_dc() {
+        // a square wave between 0.0 and 0.5 ends up swinging around 0
+        let square: Vec<f32> = (0..44100).map(|i| if (i / 100) % 2 == 0 { 0.5 } else { 0.0 }).collect();
+        let centred = remove_dc(44100, &square);
+        let last_second = &centred[22050..];
+        let mean = last_second.iter().sum::<f32>() / last_second.len() as f32;
+        assert!(mean.abs() < 0.01, "mean {}", mean);
+        assert!(last_second.iter().any(|&sample| sample > 0.2));
+        assert!(last_second.iter().any(|&sample| sample < -0.2));
+
+        // a constant level dies away
+        let centred = remove_dc(44100, &[0.8; 44100]);
+        assert!(centred[0] > 0.7);
+        assert!(centred[44099].abs() < 0.001);
+    }
+}